@@ -1,12 +1,19 @@
 use std::collections::HashSet;
-use crate::core::utils::compute_file_hash;
+use crate::core::utils::{parallel_map, sync_dir, HashingReader};
 
 use std::fs::{self, File};
-use std::io::{self, Read};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
-use sha2::{Digest, Sha256};
+use std::time::Instant;
+use tempfile::Builder;
 use zstd::stream::{copy_decode, copy_encode};
-use crate::core::models::SnapshotMetadata;
+use crate::core::models::{BlobCheck, BlobStatus, BlobVerification, SnapshotMetadata};
+use crate::error::{Error, Result};
+
+/// Name prefix of the temporary files blobs are written to before being renamed to their hash.
+/// A crash can leave them behind; only `gc` removes them, once they are older than its grace
+/// period, since a snapshot may still be writing one.
+const TEMP_PREFIX: &str = ".tmp";
 
 pub struct ContentStore {
     base_path: PathBuf,
@@ -23,29 +30,48 @@ impl ContentStore {
         fs::create_dir_all(&self.base_path)
     }
 
-    pub fn store_file(&self, file_path: &Path) -> io::Result<String> {
-        // Compute hash first
-        let hash = compute_file_hash(file_path)?;
+    /// Stores the content of `file_path` and returns its hash and size. The file is read once:
+    /// it is hashed while being compressed into a temporary file in the store, which is flushed
+    /// and then renamed to the hash, so a blob is never visible half-written. The stored blob
+    /// always matches the returned hash and size, even if the file changes while it is being
+    /// stored. An existing blob under the same hash that is empty or does not decode to that
+    /// hash, e.g. one left behind by an interrupted write of an older version, is replaced.
+    pub fn store_file(&self, file_path: &Path) -> io::Result<(String, u64)> {
+        let mut source = HashingReader::new(File::open(file_path)?);
+        let mut temp_file = Builder::new().prefix(TEMP_PREFIX).tempfile_in(&self.base_path)?;
+        copy_encode(&mut source, &mut temp_file, 3)?; // compression level 3
+        let (hash, size) = source.finish();
 
-        let content_path = self.base_path.join(&hash);
-        if !content_path.exists() {
-            // Compress and store content
-            let source = File::open(file_path)?;
-            let target = File::create(&content_path)?;
-            copy_encode(source, target, 3)?; // compression level 3
+        if !matches!(self.verify_content(&hash), Ok(true)) {
+            temp_file.as_file().sync_all()?;
+            temp_file.persist(self.base_path.join(&hash)).map_err(|e| e.error)?;
         }
 
-        Ok(hash)
+        Ok((hash, size))
     }
 
-    pub fn retrieve_file(&self, hash: &str, target_path: &Path) -> io::Result<()> {
-        let content_path = self.base_path.join(hash);
-        if !content_path.exists() {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Content not found for hash: {}", hash),
-            ));
-        }
+    /// Returns whether a blob for `hash` has been stored. Empty blobs, which can only be the
+    /// result of an interrupted write, do not count.
+    pub fn contains(&self, hash: &str) -> bool {
+        fs::metadata(self.base_path.join(hash)).is_ok_and(|metadata| metadata.len() > 0)
+    }
+
+    /// Returns whether the blob for `hash` is non-empty and consists of complete zstd frames.
+    /// Only frame and block headers are read, so this is cheap even for large blobs, but unlike
+    /// `verify_content` it cannot tell whether the content still matches the hash.
+    pub fn is_complete(&self, hash: &str) -> bool {
+        File::open(self.base_path.join(hash))
+            .and_then(has_complete_frames)
+            .unwrap_or(false)
+    }
+
+    /// Flushes the store's directory so that blobs renamed into place survive a crash.
+    pub fn sync(&self) -> io::Result<()> {
+        sync_dir(&self.base_path)
+    }
+
+    pub fn retrieve_file(&self, hash: &str, target_path: &Path) -> Result<()> {
+        let content_path = self.content_path(hash)?;
 
         // Create parent directories if needed
         if let Some(parent) = target_path.parent() {
@@ -60,14 +86,37 @@ impl ContentStore {
         Ok(())
     }
 
-    /// Returns a list of content hashes that are not referenced by any snapshot
+    /// Opens a stored blob for reading, decompressing it on the fly.
+    pub fn open(&self, hash: &str) -> Result<impl Read> {
+        let source = File::open(self.content_path(hash)?)?;
+        Ok(zstd::Decoder::new(source)?)
+    }
+
+    fn content_path(&self, hash: &str) -> Result<PathBuf> {
+        let content_path = self.base_path.join(hash);
+        if !self.contains(hash) {
+            return Err(Error::ContentMissing {
+                hash: hash.to_string(),
+            });
+        }
+        Ok(content_path)
+    }
+
+    /// Returns a list of content hashes that are not referenced by any snapshot. Temporary files
+    /// are not blobs and are never listed.
     pub fn find_orphaned_content(&self, metadata: &SnapshotMetadata) -> io::Result<Vec<String>> {
         let mut orphaned = Vec::new();
         
         // Get all content hashes currently stored
-        let stored_hashes: HashSet<String> = fs::read_dir(&self.base_path)?
+        let entries = match fs::read_dir(&self.base_path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(orphaned),
+            Err(e) => return Err(e),
+        };
+        let stored_hashes: HashSet<String> = entries
             .filter_map(|entry| entry.ok())
             .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| !name.starts_with(TEMP_PREFIX))
             .collect();
             
         // Get all hashes referenced by snapshots
@@ -119,7 +168,31 @@ impl ContentStore {
         
         Ok(())
     }
-    
+
+    /// Lists every file in the store, including temporary files left by interrupted writes,
+    /// together with its file system metadata.
+    pub fn list(&self) -> io::Result<Vec<(String, fs::Metadata)>> {
+        let entries = match fs::read_dir(&self.base_path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut blobs = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                blobs.push((entry.file_name().to_string_lossy().to_string(), metadata));
+            }
+        }
+        Ok(blobs)
+    }
+
+    pub fn remove(&self, hash: &str) -> io::Result<()> {
+        fs::remove_file(self.base_path.join(hash))
+    }
+
     pub fn cleanup(&self, to_remove: &[String]) -> io::Result<()> {
         let to_remove: std::collections::HashSet<_> = to_remove.iter().collect();
         let mut cleaned_size = 0u64;
@@ -146,24 +219,122 @@ impl ContentStore {
         Ok(())
     }
 
+    /// Returns whether the blob for `hash` exists and decodes to content with that hash. The
+    /// blob is streamed through the decoder into the hasher, so memory use does not depend on
+    /// its size.
     pub fn verify_content(&self, hash: &str) -> io::Result<bool> {
         let content_path = self.base_path.join(hash);
         if !content_path.exists() {
             return Ok(false);
         }
 
-        // Decompress and verify hash
-        let mut temp = Vec::new();
-        let source = File::open(content_path)?;
-        let mut decoder = zstd::Decoder::new(source)?;
-        decoder.read_to_end(&mut temp)?;
-
-        let mut hasher = Sha256::new();
-        hasher.update(&temp);
-        let computed_hash = format!("{:x}", hasher.finalize());
+        let mut reader = HashingReader::new(zstd::Decoder::new(File::open(content_path)?)?);
+        io::copy(&mut reader, &mut io::sink())?;
+        let (computed_hash, _) = reader.finish();
 
         Ok(computed_hash == hash)
     }
+
+    /// Like `verify_content`, but reports a blob that fails to decode as corrupt rather than
+    /// as an error, along with how much of it could be read.
+    pub fn check_blob(&self, hash: &str) -> BlobCheck {
+        let mut check = BlobCheck {
+            hash: hash.to_string(),
+            status: BlobStatus::Missing,
+            size: 0,
+        };
+        if !self.contains(hash) {
+            return check;
+        }
+
+        let mut reader = match File::open(self.base_path.join(hash)).and_then(zstd::Decoder::new) {
+            Ok(decoder) => HashingReader::new(decoder),
+            Err(_) => {
+                check.status = BlobStatus::Corrupt;
+                return check;
+            }
+        };
+        let decoded = io::copy(&mut reader, &mut io::sink());
+        let (computed_hash, size) = reader.finish();
+
+        check.size = size;
+        check.status = match decoded {
+            Ok(_) if computed_hash == hash => BlobStatus::Ok,
+            _ => BlobStatus::Corrupt,
+        };
+        check
+    }
+
+    /// Checks every blob in `hashes` on up to `jobs` threads. Results are returned in the
+    /// order of `hashes`, together with the decompressed bytes hashed per second.
+    pub fn verify_all<S: AsRef<str> + Sync>(&self, hashes: &[S], jobs: usize) -> BlobVerification {
+        let started = Instant::now();
+        let blobs = parallel_map(hashes, jobs, |hash| self.check_blob(hash.as_ref()));
+        let elapsed_secs = started.elapsed().as_secs_f64();
+
+        let bytes_verified = blobs.iter().map(|blob| blob.size).sum();
+        let bytes_per_second = if elapsed_secs > 0.0 {
+            bytes_verified as f64 / elapsed_secs
+        } else {
+            0.0
+        };
+
+        BlobVerification {
+            blobs,
+            bytes_verified,
+            elapsed_secs,
+            bytes_per_second,
+        }
+    }
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Walks the zstd frames in `file` by their headers and checks that the last one ends exactly
+/// at the end of the file. A truncated blob fails because a frame or block is cut short.
+fn has_complete_frames(mut file: File) -> io::Result<bool> {
+    let len = file.metadata()?.len();
+    let mut pos = 0;
+    while pos < len {
+        let mut frame_header = [0u8; 5];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut frame_header)?;
+        if frame_header[..4] != ZSTD_MAGIC {
+            return Ok(false);
+        }
+        let descriptor = frame_header[4];
+        let single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        let dictionary_id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+        let content_size_size = match descriptor >> 6 {
+            0 => u64::from(single_segment),
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+        pos += 5 + u64::from(!single_segment) + dictionary_id_size + content_size_size;
+
+        loop {
+            let mut block_header = [0u8; 3];
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut block_header)?;
+            let header = u32::from_le_bytes([block_header[0], block_header[1], block_header[2], 0]);
+            let block_size = match (header >> 1) & 0x3 {
+                1 => 1, // RLE blocks store a single byte
+                3 => return Ok(false),
+                _ => u64::from(header >> 3),
+            };
+            pos += 3 + block_size;
+            if header & 1 == 1 {
+                break;
+            }
+        }
+        if has_checksum {
+            pos += 4;
+        }
+    }
+
+    Ok(len > 0 && pos == len)
 }
 
 #[cfg(test)]
@@ -182,7 +353,8 @@ mod tests {
         fs::write(&test_file, b"Hello, World!")?;
 
         // Store the file
-        let hash = store.store_file(&test_file)?;
+        let (hash, size) = store.store_file(&test_file)?;
+        assert_eq!(size, 13);
 
         // Verify content exists
         assert!(store.verify_content(&hash)?);
@@ -197,14 +369,93 @@ mod tests {
             fs::read_to_string(&restored_file)?
         );
 
+        // Stream the content without writing it to disk
+        let mut streamed = String::new();
+        store.open(&hash)?.read_to_string(&mut streamed)?;
+        assert_eq!(streamed, "Hello, World!");
+        assert!(store.open("missing").is_err());
+
+        // Storing the same content again leaves a single blob and no temporary files
+        assert_eq!(store.store_file(&test_file)?.0, hash);
+        assert_eq!(fs::read_dir(test_dir.path().join(".timemachine/contents"))?.count(), 1);
+
+        // Blobs left behind by interrupted writes are replaced instead of trusted
+        let content_path = test_dir.path().join(".timemachine/contents").join(&hash);
+        fs::write(&content_path, b"")?;
+        assert!(!store.contains(&hash));
+        assert!(matches!(store.open(&hash), Err(Error::ContentMissing { .. })));
+        store.store_file(&test_file)?;
+        assert!(store.contains(&hash));
+        assert!(store.verify_content(&hash)?);
+
+        let compressed = fs::read(&content_path)?;
+        fs::write(&content_path, &compressed[..compressed.len() / 2])?;
+        assert!(store.verify_content(&hash).is_err());
+        store.store_file(&test_file)?;
+        assert!(store.verify_content(&hash)?);
+
         // Test cleanup by passing empty list (should keep all files)
         store.cleanup(&[])?;
         assert!(store.verify_content(&hash)?, "Content should still exist after cleanup with empty list");
 
         // Test cleanup by passing the hash (should remove the file)
-        store.cleanup(&[hash.clone()])?;
+        store.cleanup(std::slice::from_ref(&hash))?;
         assert!(!store.verify_content(&hash)?, "Content should be removed after cleanup with its hash");
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_is_complete() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let store = ContentStore::new(test_dir.path());
+        store.init()?;
+
+        // Mixed content spanning several zstd blocks
+        let file = test_dir.path().join("data.bin");
+        let content: Vec<u8> = (0..400_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        fs::write(&file, &content)?;
+        let (hash, _) = store.store_file(&file)?;
+        assert!(store.is_complete(&hash));
+        assert!(!store.is_complete("missing"));
+
+        // Cutting the blob anywhere is noticed
+        let content_path = test_dir.path().join(".timemachine/contents").join(&hash);
+        let compressed = fs::read(&content_path)?;
+        for len in [0, 3, 6, 10, compressed.len() / 2, compressed.len() - 1] {
+            fs::write(&content_path, &compressed[..len])?;
+            assert!(!store.is_complete(&hash), "blob cut to {} bytes", len);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_all() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let store = ContentStore::new(test_dir.path());
+        store.init()?;
+
+        // Large enough to need many reads from the decoder
+        let large_file = test_dir.path().join("large.bin");
+        fs::write(&large_file, vec![7u8; 4 * 1024 * 1024])?;
+        let small_file = test_dir.path().join("small.txt");
+        fs::write(&small_file, "small")?;
+        let (large, _) = store.store_file(&large_file)?;
+        let (small, _) = store.store_file(&small_file)?;
+
+        let content_path = test_dir.path().join(".timemachine/contents").join(&small);
+        let compressed = fs::read(&content_path)?;
+        fs::write(&content_path, &compressed[..compressed.len() - 2])?;
+
+        let verification = store.verify_all(&[large.as_str(), "missing", small.as_str()], 2);
+        let statuses: Vec<BlobStatus> = verification.blobs.iter().map(|blob| blob.status).collect();
+        assert_eq!(statuses, vec![BlobStatus::Ok, BlobStatus::Missing, BlobStatus::Corrupt]);
+        assert_eq!(verification.blobs[0].hash, large);
+        assert_eq!(verification.blobs[0].size, 4 * 1024 * 1024);
+        assert!(verification.bytes_verified >= 4 * 1024 * 1024);
+        assert_eq!(verification.failed().count(), 2);
+
+        Ok(())
+    }
+}
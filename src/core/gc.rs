@@ -0,0 +1,127 @@
+use crate::core::content::ContentStore;
+use crate::core::models::GcReport;
+use crate::core::snapshot::read_metadata;
+use crate::error::Result;
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Removes blobs that no snapshot of `dir` references. Every hash referenced by a snapshot is
+/// marked first; unmarked files in the content store, including temporary files left by
+/// interrupted writes, are then swept, except those modified within `grace`, which may belong
+/// to a snapshot that is still being written. With
+/// `dry_run`, nothing is removed but the report lists what would be.
+pub fn mark_and_sweep(dir: &str, grace: Duration, dry_run: bool) -> Result<GcReport> {
+    let base_path = Path::new(dir);
+
+    // Never fall back to the backup here: blobs only referenced by a snapshot recorded in a
+    // damaged metadata.json would be swept
+    let metadata = read_metadata(&base_path.join(".timemachine").join("metadata.json"))?;
+    let reachable: HashSet<&str> = metadata
+        .snapshots
+        .iter()
+        .flat_map(|snapshot| snapshot.file_states.iter().map(|state| state.hash.as_str()))
+        .collect();
+
+    let store = ContentStore::new(base_path);
+    let cutoff = SystemTime::now().checked_sub(grace).unwrap_or(UNIX_EPOCH);
+    let mut report = GcReport {
+        dry_run,
+        blobs_reachable: reachable.len(),
+        removed_blobs: Vec::new(),
+        reclaimed_bytes: 0,
+        recent_blobs: 0,
+    };
+
+    let mut blobs = store.list()?;
+    blobs.sort_by(|a, b| a.0.cmp(&b.0));
+    for (hash, blob_metadata) in blobs {
+        if reachable.contains(hash.as_str()) {
+            continue;
+        }
+        if blob_metadata.modified()? > cutoff {
+            report.recent_blobs += 1;
+            continue;
+        }
+
+        if !dry_run {
+            store.remove(&hash)?;
+        }
+        report.reclaimed_bytes += blob_metadata.len();
+        report.removed_blobs.push(hash);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::models::{FileState, Snapshot, SnapshotMetadata};
+    use crate::core::snapshot::save_metadata;
+    use std::fs::{self, File};
+    use std::io;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_mark_and_sweep() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let store = ContentStore::new(test_dir.path());
+        store.init()?;
+
+        let file = test_dir.path().join("kept.txt");
+        fs::write(&file, "kept")?;
+        let (kept, _) = store.store_file(&file)?;
+        fs::write(&file, "orphaned")?;
+        let (orphaned, _) = store.store_file(&file)?;
+        fs::write(&file, "fresh")?;
+        let (fresh, _) = store.store_file(&file)?;
+
+        let contents = test_dir.path().join(".timemachine").join("contents");
+        let stale_temp = ".tmpStale1".to_string();
+        fs::write(contents.join(&stale_temp), "partial")?;
+        fs::write(contents.join(".tmpFresh1"), "partial")?;
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        for hash in [&kept, &orphaned, &stale_temp] {
+            File::options().write(true).open(contents.join(hash))?.set_modified(an_hour_ago)?;
+        }
+
+        save_metadata(dir, &SnapshotMetadata {
+            snapshots: vec![Snapshot {
+                id: 1,
+                timestamp: String::new(),
+                changes: 1,
+                file_states: vec![FileState {
+                    path: "kept.txt".to_string(),
+                    size: 4,
+                    last_modified: String::new(),
+                    hash: kept.clone(),
+                }],
+                message: None,
+                tags: Vec::new(),
+            }],
+            next_id: 2,
+        })?;
+
+        let grace = Duration::from_secs(600);
+        let report = mark_and_sweep(dir, grace, true)?;
+        assert_eq!(report.removed_blobs, vec![stale_temp.clone(), orphaned.clone()]);
+        assert_eq!(report.recent_blobs, 2);
+        assert!(report.reclaimed_bytes > 0);
+        assert!(store.contains(&orphaned));
+
+        let report = mark_and_sweep(dir, grace, false)?;
+        assert_eq!(report.removed_blobs, vec![stale_temp.clone(), orphaned.clone()]);
+        assert!(!store.contains(&orphaned));
+        assert!(!contents.join(&stale_temp).exists());
+        assert!(store.contains(&kept));
+        assert!(store.contains(&fresh));
+
+        let report = mark_and_sweep(dir, Duration::ZERO, false)?;
+        assert_eq!(report.removed_blobs, vec![".tmpFresh1".to_string(), fresh.clone()]);
+        assert!(store.contains(&kept));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const IGNORE_FILE_NAME: &str = ".timemachineignore";
+
+/// Gitignore-style exclusion rules read from `.timemachineignore` files.
+///
+/// The file at the tracked root applies to the whole tree, and nested files apply to
+/// their own directory, taking precedence over rules from parent directories.
+pub struct IgnoreRules {
+    base_path: PathBuf,
+    matchers: RefCell<HashMap<String, Option<Gitignore>>>,
+}
+
+impl IgnoreRules {
+    pub fn load(base_path: &Path) -> io::Result<Self> {
+        let rules = Self {
+            base_path: base_path.to_path_buf(),
+            matchers: RefCell::new(HashMap::new()),
+        };
+
+        // Parse the root file eagerly so that invalid patterns surface straight away
+        rules.matcher_for("")?;
+
+        Ok(rules)
+    }
+
+    /// Returns true if `relative_path` (`/`-separated, relative to the tracked root) or any
+    /// of its parent directories is excluded.
+    pub fn is_ignored(&self, relative_path: &str, is_dir: bool) -> io::Result<bool> {
+        let components: Vec<&str> = relative_path.split('/').filter(|c| !c.is_empty()).collect();
+
+        for end in 1..=components.len() {
+            let prefix = components[..end].join("/");
+            let prefix_is_dir = end < components.len() || is_dir;
+            if self.matches(&prefix, prefix_is_dir)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Checks a single path against the rules of its ancestor directories without looking at
+    /// whether those ancestors are themselves ignored. Used while walking, where ignored
+    /// directories are never entered.
+    pub fn matches(&self, relative_path: &str, is_dir: bool) -> io::Result<bool> {
+        let mut dir = parent_of(relative_path);
+
+        loop {
+            if let Some(matcher) = self.matcher_for(dir)? {
+                let local = relative_path
+                    .strip_prefix(dir)
+                    .unwrap_or(relative_path)
+                    .trim_start_matches('/');
+                let matched = matcher.matched(local, is_dir);
+                if matched.is_ignore() {
+                    return Ok(true);
+                }
+                if matched.is_whitelist() {
+                    return Ok(false);
+                }
+            }
+
+            if dir.is_empty() {
+                return Ok(false);
+            }
+            dir = parent_of(dir);
+        }
+    }
+
+    fn matcher_for(&self, dir: &str) -> io::Result<Option<Gitignore>> {
+        if let Some(matcher) = self.matchers.borrow().get(dir) {
+            return Ok(matcher.clone());
+        }
+
+        let dir_path = self.base_path.join(dir);
+        let ignore_file = dir_path.join(IGNORE_FILE_NAME);
+
+        let matcher = if ignore_file.is_file() {
+            let mut builder = GitignoreBuilder::new(&dir_path);
+            if let Some(e) = builder.add(&ignore_file) {
+                eprintln!(
+                    "Warning: some patterns in '{}' were skipped: {}",
+                    ignore_file.display(),
+                    e
+                );
+            }
+            let matcher = builder.build().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Failed to parse '{}': {}", ignore_file.display(), e),
+                )
+            })?;
+            Some(matcher)
+        } else {
+            None
+        };
+
+        self.matchers
+            .borrow_mut()
+            .insert(dir.to_string(), matcher.clone());
+
+        Ok(matcher)
+    }
+}
+
+fn parent_of(relative_path: &str) -> &str {
+    relative_path
+        .rfind('/')
+        .map(|index| &relative_path[..index])
+        .unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_ignore_rules() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let base_path = test_dir.path();
+
+        fs::write(
+            base_path.join(IGNORE_FILE_NAME),
+            "*.swp\ntarget/\n/build.out\n*.log\n!keep.log\n",
+        )?;
+        fs::create_dir_all(base_path.join("nested"))?;
+        fs::write(base_path.join("nested").join(IGNORE_FILE_NAME), "local.txt\n!*.swp\n")?;
+
+        let rules = IgnoreRules::load(base_path)?;
+
+        // Plain and negated globs
+        assert!(rules.is_ignored("notes.swp", false)?);
+        assert!(rules.is_ignored("debug.log", false)?);
+        assert!(!rules.is_ignored("keep.log", false)?);
+
+        // Directory-only patterns match the directory and everything below it
+        assert!(rules.is_ignored("target", true)?);
+        assert!(rules.is_ignored("target/debug/app", false)?);
+        assert!(!rules.is_ignored("sub/target", false)?);
+
+        // Anchored patterns only match at the root
+        assert!(rules.is_ignored("build.out", false)?);
+        assert!(!rules.is_ignored("sub/build.out", false)?);
+
+        // Nested files apply to their directory and override parent rules
+        assert!(rules.is_ignored("nested/local.txt", false)?);
+        assert!(!rules.is_ignored("local.txt", false)?);
+        assert!(!rules.is_ignored("nested/editor.swp", false)?);
+
+        Ok(())
+    }
+}
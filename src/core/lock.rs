@@ -0,0 +1,177 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+use crate::error::{Error, Result};
+
+const LOCK_FILE_NAME: &str = "lock";
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a lock file without a readable PID is trusted, to cover the moment between its
+/// creation and the owner writing its PID.
+const UNOWNED_GRACE: Duration = Duration::from_secs(5);
+
+/// Advisory lock on a repository, held for as long as the value is alive.
+///
+/// The lock is a `.timemachine/lock` file containing the PID of the owning process. A lock
+/// left behind by a process that is no longer running is considered stale and taken over.
+pub struct RepoLock {
+    path: PathBuf,
+}
+
+impl RepoLock {
+    /// Acquires the lock for `dir`. Without `wait` this fails immediately if another process
+    /// holds the lock; otherwise it retries until the timeout has elapsed.
+    pub fn acquire(dir: &str, wait: Option<Duration>) -> Result<Self> {
+        let lock_dir = Path::new(dir).join(".timemachine");
+        fs::create_dir_all(&lock_dir)?;
+        let path = lock_dir.join(LOCK_FILE_NAME);
+        let deadline = wait.map(|wait| Instant::now() + wait);
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    write!(file, "{}", std::process::id())?;
+                    file.sync_all()?;
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let owner = read_owner(&path);
+                    if is_stale(&path, owner) {
+                        take_over_stale_lock(&lock_dir, &path, owner)?;
+                        continue;
+                    }
+
+                    if deadline.is_none_or(|deadline| Instant::now() >= deadline) {
+                        return Err(Error::Locked {
+                            pid: owner.unwrap_or_default(),
+                        });
+                    }
+                    thread::sleep(RETRY_INTERVAL);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for RepoLock {
+    /// Removes the lock file, unless it no longer holds our PID because another process
+    /// wrongly took it over in the meantime.
+    fn drop(&mut self) {
+        if read_owner(&self.path) == Some(std::process::id()) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn is_stale(path: &Path, owner: Option<u32>) -> bool {
+    match owner {
+        Some(pid) => !is_process_running(pid),
+        None => is_older_than(path, UNOWNED_GRACE),
+    }
+}
+
+/// Removes a lock file found to be stale. Another process may have replaced it since it was
+/// checked, so it is first moved aside under a name unique to this process, which only one
+/// process can do, and checked again. A lock that turns out to be live is put back.
+fn take_over_stale_lock(lock_dir: &Path, path: &Path, owner: Option<u32>) -> io::Result<()> {
+    let aside = lock_dir.join(format!("{}.stale.{}", LOCK_FILE_NAME, std::process::id()));
+    match fs::rename(path, &aside) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        result => result?,
+    }
+
+    let moved_owner = read_owner(&aside);
+    if is_stale(&aside, moved_owner) {
+        eprintln!(
+            "Removing stale lock left by process {}",
+            owner.map_or_else(|| "unknown".to_string(), |pid| pid.to_string())
+        );
+    } else {
+        // Fails if yet another process has created a lock since, which then owns the repository
+        match fs::hard_link(&aside, path) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+    }
+    remove_lock_file(&aside)
+}
+
+fn read_owner(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn is_older_than(path: &Path, age: Duration) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|elapsed| elapsed > age)
+}
+
+fn remove_lock_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn is_process_running(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return true;
+    }
+
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        true,
+        ProcessRefreshKind::nothing(),
+    );
+    system.process(pid).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_repo_lock() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let lock_path = test_dir.path().join(".timemachine").join(LOCK_FILE_NAME);
+
+        // A held lock blocks other acquisitions, with or without waiting
+        let lock = RepoLock::acquire(dir, None)?;
+        assert!(lock_path.exists());
+        let err = RepoLock::acquire(dir, None).err().unwrap();
+        assert!(matches!(err, Error::Locked { pid } if pid == std::process::id()));
+        assert!(RepoLock::acquire(dir, Some(Duration::from_millis(200))).is_err());
+
+        // Dropping the lock releases it
+        drop(lock);
+        assert!(!lock_path.exists());
+
+        // A lock owned by a process that no longer exists is taken over
+        fs::write(&lock_path, u32::MAX.to_string())?;
+        let lock = RepoLock::acquire(dir, None)?;
+        assert_eq!(read_owner(&lock_path), Some(std::process::id()));
+
+        // A lock that has been replaced by another owner is left alone when dropped
+        fs::write(&lock_path, "1")?;
+        drop(lock);
+        assert_eq!(read_owner(&lock_path), Some(1));
+
+        // A lock found stale but replaced by a live one before it is moved aside is put back
+        let lock_dir = test_dir.path().join(".timemachine");
+        fs::write(&lock_path, std::process::id().to_string())?;
+        take_over_stale_lock(&lock_dir, &lock_path, Some(u32::MAX))?;
+        assert_eq!(read_owner(&lock_path), Some(std::process::id()));
+        assert_eq!(fs::read_dir(&lock_dir)?.count(), 1);
+
+        Ok(())
+    }
+}
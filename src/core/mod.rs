@@ -2,4 +2,11 @@ pub mod snapshot;
 pub mod utils;
 pub mod models;
 pub mod restore;
-pub mod content;
\ No newline at end of file
+pub mod content;
+pub mod ignore_rules;
+pub mod lock;
+pub mod patch;
+pub mod scan_cache;
+pub mod verify;
+pub mod gc;
+pub mod retention;
\ No newline at end of file
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FileState {
@@ -14,21 +15,47 @@ pub struct Snapshot {
     pub timestamp: String,
     pub changes: usize,
     pub file_states: Vec<FileState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SnapshotMetadata {
     pub snapshots: Vec<Snapshot>,
+    /// ID assigned to the next snapshot. IDs are never reused, even after deletion.
+    /// Missing (zero) in repositories created before the counter existed.
+    #[serde(default)]
+    pub next_id: usize,
 }
 
-#[derive(Debug)]
+/// Differences between two snapshots, or between a snapshot and the working tree.
+#[derive(Serialize, Debug)]
 pub struct SnapshotComparison {
     pub new_files: Vec<String>,
     pub modified_files: Vec<ModifiedFileDetail>,
     pub deleted_files: Vec<String>,
+    pub renamed_files: Vec<RenamedFile>,
 }
 
-#[derive(Debug)]
+impl SnapshotComparison {
+    /// Number of files added, modified, deleted or moved.
+    pub fn change_count(&self) -> usize {
+        self.new_files.len() + self.modified_files.len() + self.deleted_files.len() + self.renamed_files.len()
+    }
+}
+
+/// A file that moved between the old and the new side. `similarity` is the percentage of
+/// matching lines: 100 for identical content, lower for renames with edits.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RenamedFile {
+    pub old_path: String,
+    pub new_path: String,
+    pub similarity: u8,
+}
+
+#[derive(Serialize, Debug)]
 pub struct ModifiedFileDetail {
     pub path: String,
     pub old_size: u64,
@@ -39,26 +66,193 @@ pub struct ModifiedFileDetail {
     pub new_last_modified: String,
 }
 
+/// How a file differs between the old and the new side of a diff.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeStatus {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+/// Line-level changes to a single file. Binary files carry no line counts and no patch.
+/// `old_path` is only set for renamed files; `path` is always the new location.
+#[derive(Serialize, Debug)]
+pub struct FileDiff {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    pub status: ChangeStatus,
+    pub binary: bool,
+    pub insertions: usize,
+    pub deletions: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+}
+
+/// Files a restore adds, overwrites, deletes or leaves alone, relative to the tracked root.
+#[derive(Serialize, Debug)]
 pub struct RestoreReport {
     pub added: Vec<String>,
     pub modified: Vec<String>,
     pub deleted: Vec<String>,
     pub unchanged: Vec<String>,
+    /// Files moved back to their snapshot location instead of being deleted and re-added.
+    pub renamed: Vec<RenamedFile>,
 }
 
-
+/// Summary of a snapshot as shown by `list`. `total_size` is 0 unless detailed output was requested.
+#[derive(Serialize, Debug)]
 pub struct SnapshotListInfo {
     pub id: usize,
     pub timestamp: String,
     pub changes: usize,
     pub total_size: u64,
+    pub message: Option<String>,
+    pub tags: Vec<String>,
 }
 
+/// Working tree state compared to the latest snapshot.
+#[derive(Serialize, Debug)]
 pub struct StatusInfo {
     pub has_uncommitted_changes: bool,
     pub modified_files: Vec<String>,
     pub new_files: Vec<String>,
     pub deleted_files: Vec<String>,
+    pub renamed_files: Vec<RenamedFile>,
     pub available_space: u64,
     pub latest_snapshot_id: Option<usize>,
-}
\ No newline at end of file
+}
+
+/// A blob referenced by snapshots that is missing or does not decode to its hash, together
+/// with the snapshots and paths that refer to it.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DamagedBlob {
+    pub hash: String,
+    pub snapshot_ids: Vec<usize>,
+    pub paths: Vec<String>,
+}
+
+/// Result of checking a repository's integrity. Orphaned blobs waste space but do not make
+/// the repository unhealthy.
+#[derive(Serialize, Debug)]
+pub struct VerifyReport {
+    /// Why `metadata.json` could not be read, and whether its backup was checked instead.
+    pub metadata_error: Option<String>,
+    pub snapshots_checked: usize,
+    pub duplicate_ids: Vec<usize>,
+    /// Distinct blobs referenced by any snapshot.
+    pub blobs_referenced: usize,
+    /// Referenced blobs that were decompressed and hashed; fewer than `blobs_referenced` when sampling.
+    pub blobs_verified: usize,
+    /// Decompressed bytes hashed, and how many of them were hashed per second.
+    pub bytes_verified: u64,
+    pub bytes_per_second: f64,
+    pub missing_blobs: Vec<DamagedBlob>,
+    pub corrupt_blobs: Vec<DamagedBlob>,
+    pub orphaned_blobs: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_healthy(&self) -> bool {
+        self.metadata_error.is_none()
+            && self.duplicate_ids.is_empty()
+            && self.missing_blobs.is_empty()
+            && self.corrupt_blobs.is_empty()
+    }
+}
+
+/// Outcome of decompressing and hashing a single blob.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobStatus {
+    Ok,
+    Missing,
+    Corrupt,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BlobCheck {
+    pub hash: String,
+    pub status: BlobStatus,
+    /// Decompressed bytes read before the blob ended or failed to decode.
+    pub size: u64,
+}
+
+/// Results of checking a set of blobs, in the order they were requested.
+#[derive(Serialize, Debug)]
+pub struct BlobVerification {
+    pub blobs: Vec<BlobCheck>,
+    pub bytes_verified: u64,
+    pub elapsed_secs: f64,
+    pub bytes_per_second: f64,
+}
+
+impl BlobVerification {
+    pub fn failed(&self) -> impl Iterator<Item = &BlobCheck> {
+        self.blobs.iter().filter(|blob| blob.status != BlobStatus::Ok)
+    }
+}
+
+/// Blobs removed by garbage collection, or that would be removed by a dry run.
+#[derive(Serialize, Debug)]
+pub struct GcReport {
+    pub dry_run: bool,
+    /// Distinct blobs referenced by any snapshot.
+    pub blobs_reachable: usize,
+    pub removed_blobs: Vec<String>,
+    pub reclaimed_bytes: u64,
+    /// Unreferenced blobs kept because they are younger than the grace period.
+    pub recent_blobs: usize,
+}
+
+/// How `take_snapshot` records a snapshot. The defaults record it without a message, fail
+/// immediately if the repository is locked, use every available core and skip the snapshot if
+/// nothing changed.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotOptions {
+    pub message: Option<String>,
+    /// How long to wait for another process to release the repository lock.
+    pub wait: Option<Duration>,
+    /// Number of threads used to hash and compress files.
+    pub jobs: Option<usize>,
+    /// Record a snapshot even if nothing changed since the latest one.
+    pub allow_empty: bool,
+}
+
+/// Which snapshots `prune` keeps. Each `keep_*` count keeps the newest snapshot of that many
+/// distinct periods, most recent first; a snapshot kept by any rule survives. A policy that
+/// keeps nothing at all is treated as keeping everything.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_hourly: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    /// Snapshots carrying any of these tags are always kept.
+    pub keep_tags: Vec<String>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_hourly == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+            && self.keep_tags.is_empty()
+    }
+}
+
+/// Snapshots removed by `prune`, or that would be removed by a dry run, and the content
+/// garbage collection that followed. `gc` is absent for dry runs.
+#[derive(Serialize, Debug)]
+pub struct PruneReport {
+    pub dry_run: bool,
+    pub kept: Vec<usize>,
+    pub removed: Vec<SnapshotListInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gc: Option<GcReport>,
+}
@@ -0,0 +1,365 @@
+use crate::core::models::{ChangeStatus, FileDiff, RenamedFile};
+use crate::error::Result;
+use similar::{ChangeTag, TextDiff};
+use std::collections::HashSet;
+use std::io::Read;
+
+/// How many leading bytes are inspected for NUL bytes when deciding whether a file is binary.
+const BINARY_SNIFF_LEN: usize = 8000;
+
+/// Files larger than this are not diffed line by line and are reported like binary files.
+pub const MAX_TEXT_DIFF_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Similarity-based rename detection is skipped when the number of candidate pairs exceeds
+/// the square of this limit, since every pair has to be diffed.
+const RENAME_LIMIT: usize = 1000;
+
+/// Similarity-based rename detection is also skipped when the candidate files would take more
+/// than this many bytes to hold in memory at once.
+const RENAME_BYTES_LIMIT: u64 = 256 * 1024 * 1024;
+
+/// The files on one side of a comparison.
+pub trait FileSource {
+    /// The size of `path` as recorded when it was scanned.
+    fn size(&self, path: &str) -> u64;
+
+    /// Opens `path` for reading its content.
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>>;
+}
+
+/// Treats content as binary when its first `BINARY_SNIFF_LEN` bytes contain a NUL byte, the
+/// heuristic git uses. Only the start of `content` is inspected.
+pub fn is_binary(content: &[u8]) -> bool {
+    content[..content.len().min(BINARY_SNIFF_LEN)].contains(&0)
+}
+
+/// Reads `path` from `source` for diffing as text. Returns `None` for binary files, which are
+/// recognized by their first bytes without reading the rest, and for files that are not valid
+/// UTF-8 or larger than `MAX_TEXT_DIFF_SIZE`.
+pub fn read_text(source: &impl FileSource, path: &str) -> Result<Option<String>> {
+    if source.size(path) > MAX_TEXT_DIFF_SIZE {
+        return Ok(None);
+    }
+
+    // The size may have changed since the scan, so the limit is enforced while reading too
+    let mut reader = source.open(path)?.take(MAX_TEXT_DIFF_SIZE + 1);
+    let mut content = Vec::new();
+    (&mut reader).take(BINARY_SNIFF_LEN as u64).read_to_end(&mut content)?;
+    if is_binary(&content) {
+        return Ok(None);
+    }
+    reader.read_to_end(&mut content)?;
+    if content.len() as u64 > MAX_TEXT_DIFF_SIZE {
+        return Ok(None);
+    }
+
+    Ok(String::from_utf8(content).ok())
+}
+
+/// Builds the diff for a single file from its text as returned by `read_text`, where `None`
+/// marks a binary file. `old` is empty for added files and `new` is empty for deleted ones;
+/// the patch headers use `/dev/null` for the missing side.
+pub fn diff_file(
+    path: &str,
+    status: ChangeStatus,
+    old: Option<&str>,
+    new: Option<&str>,
+    context_lines: usize,
+) -> FileDiff {
+    build_diff(path, None, status, old, new, context_lines)
+}
+
+/// Builds the diff for a file that moved from `renamed.old_path` to `renamed.new_path`.
+pub fn diff_renamed_file(
+    renamed: &RenamedFile,
+    old: Option<&str>,
+    new: Option<&str>,
+    context_lines: usize,
+) -> FileDiff {
+    build_diff(
+        &renamed.new_path,
+        Some(&renamed.old_path),
+        ChangeStatus::Renamed,
+        old,
+        new,
+        context_lines,
+    )
+}
+
+/// Pairs text files from `deleted_files` and `new_files` whose lines are at least `threshold`
+/// percent similar as renames with edits, removing them from both lists. The most similar pairs
+/// are matched first. Binary files are never paired; identical files should already have been
+/// paired by hash. As in git, files whose sizes differ by more than the threshold allows are not
+/// compared, and only files with such a potential partner are read.
+pub fn find_similar_renames(
+    new_files: &mut Vec<String>,
+    deleted_files: &mut Vec<String>,
+    old: &impl FileSource,
+    new: &impl FileSource,
+    threshold: u8,
+) -> Result<Vec<RenamedFile>> {
+    if new_files.is_empty()
+        || deleted_files.is_empty()
+        || new_files.len() * deleted_files.len() > RENAME_LIMIT * RENAME_LIMIT
+    {
+        return Ok(Vec::new());
+    }
+
+    let old_sizes: Vec<u64> = deleted_files.iter().map(|path| old.size(path)).collect();
+    let new_sizes: Vec<u64> = new_files.iter().map(|path| new.size(path)).collect();
+    let comparable = |a: u64, b: u64| {
+        a.max(b) <= MAX_TEXT_DIFF_SIZE && a.abs_diff(b) * 100 <= a.max(b) * (100 - threshold.min(100) as u64)
+    };
+    let old_wanted: Vec<bool> = old_sizes
+        .iter()
+        .map(|&a| new_sizes.iter().any(|&b| comparable(a, b)))
+        .collect();
+    let new_wanted: Vec<bool> = new_sizes
+        .iter()
+        .map(|&b| old_sizes.iter().any(|&a| comparable(a, b)))
+        .collect();
+
+    let wanted_bytes: u64 = old_sizes
+        .iter()
+        .zip(&old_wanted)
+        .chain(new_sizes.iter().zip(&new_wanted))
+        .filter(|(_, &wanted)| wanted)
+        .map(|(size, _)| size)
+        .sum();
+    if wanted_bytes > RENAME_BYTES_LIMIT {
+        return Ok(Vec::new());
+    }
+
+    let read_wanted = |paths: &[String], wanted: &[bool], source: &dyn Fn(&str) -> Result<Option<String>>| {
+        paths
+            .iter()
+            .zip(wanted)
+            .map(|(path, &wanted)| if wanted { source(path) } else { Ok(None) })
+            .collect::<Result<Vec<_>>>()
+    };
+    let old_texts = read_wanted(deleted_files, &old_wanted, &|path| read_text(old, path))?;
+    let new_texts = read_wanted(new_files, &new_wanted, &|path| read_text(new, path))?;
+
+    let mut candidates = Vec::new();
+    for (old_index, old_text) in old_texts.iter().enumerate() {
+        let Some(old_text) = old_text else { continue };
+        let old_lines = old_text.lines().count();
+        for (new_index, new_text) in new_texts.iter().enumerate() {
+            let Some(new_text) = new_text else { continue };
+            if !comparable(old_sizes[old_index], new_sizes[new_index]) {
+                continue;
+            }
+            // The ratio can never exceed what the line counts alone allow
+            let new_lines = new_text.lines().count();
+            let best_case = 2 * old_lines.min(new_lines) * 100 / (old_lines + new_lines).max(1);
+            if best_case < threshold as usize {
+                continue;
+            }
+            let similarity = (TextDiff::from_lines(old_text, new_text).ratio() * 100.0) as u8;
+            if similarity >= threshold {
+                candidates.push((similarity, old_index, new_index));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+
+    let mut used_old = HashSet::new();
+    let mut used_new = HashSet::new();
+    let mut renamed_files = Vec::new();
+    for (similarity, old_index, new_index) in candidates {
+        if used_old.contains(&old_index) || used_new.contains(&new_index) {
+            continue;
+        }
+        used_old.insert(old_index);
+        used_new.insert(new_index);
+        renamed_files.push(RenamedFile {
+            old_path: deleted_files[old_index].clone(),
+            new_path: new_files[new_index].clone(),
+            similarity,
+        });
+    }
+
+    let unpaired = |paths: &[String], used: &HashSet<usize>| {
+        paths
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !used.contains(index))
+            .map(|(_, path)| path.clone())
+            .collect()
+    };
+    *deleted_files = unpaired(deleted_files, &used_old);
+    *new_files = unpaired(new_files, &used_new);
+    renamed_files.sort_by(|a, b| a.new_path.cmp(&b.new_path));
+    Ok(renamed_files)
+}
+
+fn build_diff(
+    path: &str,
+    old_path: Option<&str>,
+    status: ChangeStatus,
+    old: Option<&str>,
+    new: Option<&str>,
+    context_lines: usize,
+) -> FileDiff {
+    let (Some(old_text), Some(new_text)) = (old, new) else {
+        return FileDiff {
+            path: path.to_string(),
+            old_path: old_path.map(str::to_string),
+            status,
+            binary: true,
+            insertions: 0,
+            deletions: 0,
+            patch: None,
+        };
+    };
+
+    let diff = TextDiff::from_lines(old_text, new_text);
+    let (mut insertions, mut deletions) = (0, 0);
+    for change in diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Insert => insertions += 1,
+            ChangeTag::Delete => deletions += 1,
+            ChangeTag::Equal => {}
+        }
+    }
+
+    let old_header = match status {
+        ChangeStatus::Added => "/dev/null".to_string(),
+        _ => format!("a/{}", old_path.unwrap_or(path)),
+    };
+    let new_header = match status {
+        ChangeStatus::Deleted => "/dev/null".to_string(),
+        _ => format!("b/{}", path),
+    };
+    let patch = diff
+        .unified_diff()
+        .context_radius(context_lines)
+        .header(&old_header, &new_header)
+        .to_string();
+
+    FileDiff {
+        path: path.to_string(),
+        old_path: old_path.map(str::to_string),
+        status,
+        binary: false,
+        insertions,
+        deletions,
+        patch: Some(patch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// Files held in memory, keyed by path. Unknown paths read as a small binary file.
+    struct MemoryFiles {
+        files: HashMap<&'static str, &'static [u8]>,
+        opened: RefCell<Vec<String>>,
+    }
+
+    impl MemoryFiles {
+        fn new<const N: usize>(files: [(&'static str, &'static [u8]); N]) -> Self {
+            Self {
+                files: HashMap::from(files),
+                opened: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn content(&self, path: &str) -> &'static [u8] {
+            self.files.get(path).copied().unwrap_or(b"\0binary")
+        }
+    }
+
+    impl FileSource for MemoryFiles {
+        fn size(&self, path: &str) -> u64 {
+            self.content(path).len() as u64
+        }
+
+        fn open(&self, path: &str) -> Result<Box<dyn Read + '_>> {
+            self.opened.borrow_mut().push(path.to_string());
+            Ok(Box::new(self.content(path)))
+        }
+    }
+
+    #[test]
+    fn test_diff_file() {
+        let old = "one\ntwo\nthree\nfour\nfive\n";
+        let new = "one\ntwo\n3\nfour\nfive\nsix\n";
+
+        let diff = diff_file("numbers.txt", ChangeStatus::Modified, Some(old), Some(new), 1);
+        assert!(!diff.binary);
+        assert_eq!((diff.insertions, diff.deletions), (2, 1));
+        let patch = diff.patch.unwrap();
+        assert!(patch.starts_with("--- a/numbers.txt\n+++ b/numbers.txt\n"));
+        assert!(patch.contains("-three\n+3\n"));
+        assert!(patch.contains("+six\n"));
+        assert!(!patch.contains(" one\n"), "context should be limited to one line");
+
+        let added = diff_file("new.txt", ChangeStatus::Added, Some(""), Some("hello\n"), 3);
+        assert!(added.patch.unwrap().starts_with("--- /dev/null\n+++ b/new.txt\n"));
+        assert_eq!((added.insertions, added.deletions), (1, 0));
+
+        let binary = diff_file("image.png", ChangeStatus::Modified, None, Some("text"), 3);
+        assert!(binary.binary);
+        assert!(binary.patch.is_none());
+    }
+
+    #[test]
+    fn test_read_text() -> Result<()> {
+        let files = MemoryFiles::new([
+            ("notes.txt", b"hello\n".as_slice()),
+            ("latin1.txt", b"caf\xe9\n".as_slice()),
+        ]);
+        assert_eq!(read_text(&files, "notes.txt")?.as_deref(), Some("hello\n"));
+        assert_eq!(read_text(&files, "latin1.txt")?, None);
+        assert_eq!(read_text(&files, "image.png")?, None);
+
+        // Only the start of a file is inspected for NUL bytes
+        let mut late_nul = vec![b'a'; BINARY_SNIFF_LEN];
+        late_nul.push(0);
+        assert!(!is_binary(&late_nul));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_similar_renames() -> Result<()> {
+        let files = MemoryFiles::new([
+            ("old/config.txt", b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n".as_slice()),
+            ("new/config.txt", b"a\nb\nc\nd\ne\nf\ng\nh\ni\nJ\n".as_slice()),
+            ("removed.txt", b"nothing\nin\ncommon\n".as_slice()),
+            ("added.txt", b"completely\ndifferent\n".as_slice()),
+            ("old/log.txt", b"a\nb\nc\nd\nthis line is far longer than any line in the new file\n".as_slice()),
+            ("new/log.txt", b"a\nb\nc\nd\ne\n".as_slice()),
+        ]);
+
+        let mut new_files = vec!["added.txt".to_string(), "new/config.txt".to_string(), "new.bin".to_string()];
+        let mut deleted_files = vec!["old.bin".to_string(), "old/config.txt".to_string(), "removed.txt".to_string()];
+        let renamed = find_similar_renames(&mut new_files, &mut deleted_files, &files, &files, 50)?;
+
+        assert_eq!(renamed.len(), 1);
+        assert_eq!(renamed[0].old_path, "old/config.txt");
+        assert_eq!(renamed[0].new_path, "new/config.txt");
+        assert_eq!(renamed[0].similarity, 90);
+        assert_eq!(new_files, vec!["added.txt", "new.bin"]);
+        assert_eq!(deleted_files, vec!["old.bin", "removed.txt"]);
+
+        // Most lines match, but the sizes are too far apart to be compared or even read
+        let mut new_files = vec!["new/log.txt".to_string()];
+        let mut deleted_files = vec!["old/log.txt".to_string()];
+        files.opened.borrow_mut().clear();
+        assert!(find_similar_renames(&mut new_files, &mut deleted_files, &files, &files, 50)?.is_empty());
+        assert!(files.opened.borrow().is_empty());
+
+        let old = read_text(&files, "old/config.txt")?;
+        let new = read_text(&files, "new/config.txt")?;
+        let diff = diff_renamed_file(&renamed[0], old.as_deref(), new.as_deref(), 0);
+        assert_eq!(diff.old_path.as_deref(), Some("old/config.txt"));
+        assert!(diff.patch.unwrap().starts_with("--- a/old/config.txt\n+++ b/new/config.txt\n"));
+
+        Ok(())
+    }
+}
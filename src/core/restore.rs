@@ -1,45 +1,121 @@
-use crate::core::models::{FileState, RestoreReport, Snapshot, SnapshotMetadata};
+use crate::core::models::{FileState, RestoreReport, Snapshot};
 use crate::core::snapshot::{
-    collect_file_states, find_deleted_files, find_modified_files, find_new_files,
-    load_all_snapshots,
+    collect_file_states, compare_with_working_tree, find_deleted_files, find_modified_files, find_new_files, find_renamed_files,
+    get_snapshot, load_all_snapshots, normalize_path,
 };
 use crate::core::content::ContentStore;
+use crate::error::Result;
 
-use serde_json;
 use std::collections::HashMap;
-use std::io::ErrorKind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{fs, io};
 use sysinfo::{DiskRefreshKind, Disks};
 
-pub fn has_uncommitted_changes(dir: &str) -> io::Result<bool> {
-    let current_files_state = collect_file_states(&dir)?;
+/// Limits an operation to files at or below a set of `/`-separated paths relative to the
+/// tracked root. A filter without paths matches every file.
+pub struct PathFilter {
+    paths: Vec<String>,
+}
+
+impl PathFilter {
+    pub fn new(paths: &[String]) -> Self {
+        Self {
+            paths: paths.iter().map(|path| normalize_path(path)).collect(),
+        }
+    }
+
+    pub fn all() -> Self {
+        Self { paths: Vec::new() }
+    }
+
+    pub fn matches(&self, file_path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|path| Self::covers(path, file_path))
+    }
+
+    /// Returns the first filter path that matches none of `file_paths`.
+    pub fn find_unmatched<'a>(&'a self, file_paths: &[&str]) -> Option<&'a str> {
+        self.paths
+            .iter()
+            .find(|path| !file_paths.iter().any(|file_path| Self::covers(path, file_path)))
+            .map(String::as_str)
+    }
+
+    fn covers(path: &str, file_path: &str) -> bool {
+        path.is_empty()
+            || path == "."
+            || file_path == path
+            || file_path
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+/// Whether the files selected by `filter` differ from the latest snapshot, by the same rules as
+/// `status` and the check `snapshot` uses to skip unchanged trees: timestamps alone do not count.
+pub fn has_uncommitted_changes(dir: &str, filter: &PathFilter) -> Result<bool> {
+    let current_states: Vec<FileState> = collect_file_states(dir)?
+        .into_iter()
+        .filter(|state| filter.matches(&state.path))
+        .collect();
     let all_snapshots = load_all_snapshots(dir)?;
 
-    if let Some(latest_snapshot) = all_snapshots.snapshots.last() {
-        println!("Latest snapshot: {:?}", latest_snapshot.file_states);
-        println!("Current files: {:?}", current_files_state);
-        Ok(latest_snapshot.file_states != current_files_state)
-    } else {
-        Ok(!current_files_state.is_empty())
+    match all_snapshots.snapshots.last() {
+        Some(latest_snapshot) => {
+            let snapshot_states: Vec<FileState> = latest_snapshot
+                .file_states
+                .iter()
+                .filter(|state| filter.matches(&state.path))
+                .cloned()
+                .collect();
+            Ok(compare_with_working_tree(dir, &snapshot_states, &current_states)?.change_count() > 0)
+        }
+        None => Ok(!current_states.is_empty()),
     }
 }
 
-pub fn has_available_space(dir: &str, snapshot: &Snapshot) -> io::Result<bool> {
-    let base_dir = Path::new(&dir);
-    let abs_path = base_dir.canonicalize()?;
+/// Free space, in bytes, on the disk holding `dir`.
+pub fn available_space(dir: &str) -> io::Result<u64> {
+    let abs_path = resolve_path(Path::new(dir))?;
 
     let disks = Disks::new_with_refreshed_list_specifics(DiskRefreshKind::everything());
 
-    let available_space = disks
+    Ok(disks
         .iter()
         .find(|disk| abs_path.starts_with(disk.mount_point()))
         .map(|disk| disk.available_space())
-        .unwrap_or(0);
+        .unwrap_or(0))
+}
 
-    let required_space = snapshot.file_states.iter().map(|s| s.size).sum();
+/// Makes `path` absolute with symlinks resolved, like `canonicalize`, but also for a path that
+/// does not exist yet: its nearest existing ancestor is canonicalized and the rest appended.
+pub fn resolve_path(path: &Path) -> io::Result<PathBuf> {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+            }
+            // `..` and `.` endings of a missing path are left for canonicalize to reject
+            _ => break,
+        }
+    }
 
-    Ok(available_space >= required_space)
+    let mut resolved = existing.canonicalize()?;
+    for name in rest.into_iter().rev() {
+        resolved.push(name);
+    }
+    Ok(resolved)
+}
+
+pub fn required_space(snapshot: &Snapshot, filter: &PathFilter) -> u64 {
+    snapshot
+        .file_states
+        .iter()
+        .filter(|s| filter.matches(&s.path))
+        .map(|s| s.size)
+        .sum()
 }
 
 pub fn validate_permissions(dir: &str) -> io::Result<()> {
@@ -58,27 +134,30 @@ pub fn generate_restore_report(
     old_snapshot: &HashMap<String, &FileState>,
     new_snapshot: &HashMap<String, &FileState>,
 ) -> RestoreReport {
-    let added = find_new_files(new_snapshot, old_snapshot);
+    let mut added = find_new_files(new_snapshot, old_snapshot);
+    let mut deleted = find_deleted_files(old_snapshot, new_snapshot);
+    let renamed = find_renamed_files(old_snapshot, new_snapshot, &mut added, &mut deleted);
     let modified: Vec<String> = find_modified_files(old_snapshot, new_snapshot)
         .into_iter()
         .map(|detail| detail.path)
         .collect();
-    let deleted = find_deleted_files(old_snapshot, new_snapshot);
 
     // Unchanged files can be derived by excluding modified and added files
-    let unchanged = old_snapshot
+    let mut unchanged: Vec<String> = old_snapshot
         .keys()
         .filter(|path| {
             new_snapshot.contains_key(*path) && !added.contains(path) && !modified.contains(path)
         })
         .cloned()
         .collect();
+    unchanged.sort();
 
     RestoreReport {
         added,
         modified,
         deleted,
         unchanged,
+        renamed,
     }
 }
 
@@ -86,47 +165,103 @@ pub fn perform_restore(
     base_path: &Path,
     snapshot_id: usize,
     report: &RestoreReport,
-) -> io::Result<()> {
-    let metadata_path = base_path.join(".timemachine").join("metadata.json");
-    let metadata_content = fs::read_to_string(&metadata_path)?;
-    let metadata: SnapshotMetadata = serde_json::from_str(&metadata_content)?;
+) -> Result<()> {
+    let metadata = load_all_snapshots(&base_path.to_string_lossy())?;
 
     let store = ContentStore::new(base_path);
 
-    let snapshot = metadata
-        .snapshots
-        .iter()
-        .find(|s| s.id == snapshot_id)
-        .ok_or_else(|| {
-            io::Error::new(
-                ErrorKind::NotFound,
-                format!(
-                    "Snapshot {} does not exist. Available snapshots: {}",
-                    snapshot_id,
-                    metadata
-                        .snapshots
-                        .iter()
-                        .map(|s| s.id.to_string())
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                ),
-            )
-        })?;
+    let snapshot = get_snapshot(&metadata, snapshot_id)?;
+
+    // Handle deletions first, so a path that is a directory now and a file in the snapshot (or
+    // the reverse) is free before anything is written to it
+    for path in &report.deleted {
+        let target_path = base_path.join(path);
+        if target_path.exists() {
+            fs::remove_file(&target_path)?;
+            remove_empty_parents(base_path, &target_path)?;
+        }
+    }
+
+    // Move renamed files back instead of deleting and re-adding them
+    for renamed in &report.renamed {
+        let source_path = base_path.join(&renamed.old_path);
+        let target_path = base_path.join(&renamed.new_path);
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&source_path, &target_path)?;
+        remove_empty_parents(base_path, &source_path)?;
+    }
 
     // Restore files
     for file_state in &snapshot.file_states {
         let target_path = base_path.join(&file_state.path);
         if report.added.contains(&file_state.path) || report.modified.contains(&file_state.path) {
+            clear_conflicts(base_path, &target_path)?;
             store.retrieve_file(&file_state.hash, &target_path)?;
         }
     }
 
-    // Handle deletions
-    for path in &report.deleted {
-        let target_path = base_path.join(path);
-        if target_path.exists() {
-            fs::remove_file(&target_path)?;
+    Ok(())
+}
+
+/// Writes the files of `snapshot` selected by `filter` from the content store of `base_path`
+/// into `target`, returning the paths written. Nothing in `base_path` is modified.
+pub fn materialize_snapshot(
+    base_path: &Path,
+    snapshot: &Snapshot,
+    filter: &PathFilter,
+    target: &Path,
+) -> Result<Vec<String>> {
+    let store = ContentStore::new(base_path);
+    let mut written = Vec::new();
+
+    for file_state in &snapshot.file_states {
+        if filter.matches(&file_state.path) {
+            store.retrieve_file(&file_state.hash, &target.join(&file_state.path))?;
+            written.push(file_state.path.clone());
+        }
+    }
+
+    written.sort();
+    Ok(written)
+}
+
+/// Makes room for a file at `path`: removes a directory standing where the file goes, along with
+/// anything left in it, and any file standing where one of its parent directories goes.
+fn clear_conflicts(base_path: &Path, path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+        fs::remove_dir_all(path)?;
+    }
+
+    let mut current = path.parent();
+    while let Some(dir) = current {
+        if dir == base_path || !dir.starts_with(base_path) {
+            break;
+        }
+        if fs::symlink_metadata(dir).is_ok_and(|metadata| !metadata.is_dir()) {
+            fs::remove_file(dir)?;
+            break;
+        }
+        current = dir.parent();
+    }
+
+    Ok(())
+}
+
+/// Removes directories left empty by a deletion, walking up until `base_path` is reached.
+fn remove_empty_parents(base_path: &Path, path: &Path) -> io::Result<()> {
+    let mut current = path.parent();
+
+    while let Some(dir) = current {
+        if dir == base_path || !dir.starts_with(base_path) {
+            break;
         }
+        if fs::read_dir(dir)?.next().is_some() {
+            break;
+        }
+        fs::remove_dir(dir)?;
+        current = dir.parent();
     }
 
     Ok(())
@@ -138,6 +273,8 @@ mod tests {
     use std::fs;
     use tempfile::tempdir;
     use crate::core::content::ContentStore;
+    use crate::core::models::SnapshotMetadata;
+    use crate::core::snapshot::store_file_states;
 
     #[test]
     fn test_perform_restore_with_content() -> io::Result<()> {
@@ -151,7 +288,7 @@ mod tests {
         // Initialize content store and store the file
         let store = ContentStore::new(base_path);
         store.init()?;
-        let hash = store.store_file(&file1_path)?;
+        let (hash, _) = store.store_file(&file1_path)?;
 
         // Create metadata with the actual hash
         let metadata = SnapshotMetadata {
@@ -165,7 +302,10 @@ mod tests {
                     size: 12,
                     last_modified: "timestamp".to_string(),
                 }],
+                message: None,
+                tags: vec![],
             }],
+            next_id: 2,
         };
 
         let metadata_dir = base_path.join(".timemachine");
@@ -184,6 +324,7 @@ mod tests {
             modified: vec![],
             deleted: vec![],
             unchanged: vec![],
+            renamed: vec![],
         };
 
         perform_restore(base_path, 1, &report)?;
@@ -195,7 +336,43 @@ mod tests {
     }
 
     #[test]
-    fn test_has_available_space() -> io::Result<()> {
+    fn test_materialize_snapshot() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let target_dir = tempdir()?;
+        let base_path = test_dir.path();
+
+        let nested = base_path.join("src");
+        fs::create_dir_all(&nested)?;
+        fs::write(nested.join("main.rs"), "fn main() {}")?;
+        fs::write(base_path.join("README.md"), "readme")?;
+
+        let mut file_states = collect_file_states(base_path.to_str().unwrap())?;
+        store_file_states(base_path.to_str().unwrap(), &mut file_states, 2)?;
+        let snapshot = Snapshot {
+            id: 1,
+            timestamp: "".to_string(),
+            changes: 2,
+            file_states,
+            message: None,
+            tags: vec![],
+        };
+
+        let written = materialize_snapshot(
+            base_path,
+            &snapshot,
+            &PathFilter::new(&["src".to_string()]),
+            target_dir.path(),
+        )?;
+
+        assert_eq!(written, vec!["src/main.rs"]);
+        assert_eq!(fs::read_to_string(target_dir.path().join("src/main.rs"))?, "fn main() {}");
+        assert!(!target_dir.path().join("README.md").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_available_space() -> io::Result<()> {
         let test_dir = tempdir()?;
         let dir = test_dir.path().to_str().unwrap();
         let content = "test content";
@@ -212,10 +389,12 @@ mod tests {
             timestamp: "".to_string(),
             changes: 1,
             file_states: vec![file_state],
+            message: None,
+            tags: vec![],
         };
 
         // Test with small file
-        assert!(has_available_space(dir, &snapshot)?);
+        assert!(available_space(dir)? >= required_space(&snapshot, &PathFilter::all()));
 
         // Test with extremely large file
         let large_file_state = FileState {
@@ -230,9 +409,11 @@ mod tests {
             timestamp: "".to_string(),
             changes: 1,
             file_states: vec![large_file_state],
+            message: None,
+            tags: vec![],
         };
 
-        assert!(!has_available_space(dir, &large_snapshot)?);
+        assert!(available_space(dir)? < required_space(&large_snapshot, &PathFilter::all()));
 
         Ok(())
     }
@@ -251,6 +432,21 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_path_filter() {
+        let filter = PathFilter::new(&["src/config.toml".to_string(), "./docs/".to_string()]);
+
+        assert!(filter.matches("src/config.toml"));
+        assert!(filter.matches("docs/guide.md"));
+        assert!(filter.matches("docs/api/index.md"));
+        assert!(!filter.matches("src/main.rs"));
+        assert!(!filter.matches("docs-old/guide.md"));
+        assert!(PathFilter::all().matches("anything.txt"));
+
+        assert_eq!(filter.find_unmatched(&["docs/guide.md"]), Some("src/config.toml"));
+        assert_eq!(filter.find_unmatched(&["docs/guide.md", "src/config.toml"]), None);
+    }
+
     #[test]
     fn test_generate_restore_report() {
         use chrono::Utc;
@@ -312,7 +508,7 @@ mod tests {
         // Initialize content store
         let store = ContentStore::new(test_dir.path());
         store.init()?;
-        let hash = store.store_file(&file)?;
+        let (hash, _) = store.store_file(&file)?;
 
         // Initialize .timemachine directory and metadata
         let metadata_dir = test_dir.path().join(".timemachine");
@@ -329,7 +525,10 @@ mod tests {
                     size: fs::metadata(&file)?.len(),
                     last_modified: fs::metadata(&file)?.modified()?.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs().to_string(),
                 }],
+                message: None,
+                tags: vec![],
             }],
+            next_id: 2,
         };
 
         fs::write(
@@ -338,16 +537,14 @@ mod tests {
         )?;
 
         // Initially no uncommitted changes
-        let result = has_uncommitted_changes(test_path)?;
-        println!("Initial check: {}", result);
+        let result = has_uncommitted_changes(test_path, &PathFilter::all())?;
         assert!(!result);
 
         // Modify file
         fs::write(&file, "modified content")?;
 
         // Now should have uncommitted changes
-        let result = has_uncommitted_changes(test_path)?;
-        println!("After modification: {}", result);
+        let result = has_uncommitted_changes(test_path, &PathFilter::all())?;
         assert!(result);
 
         Ok(())
@@ -0,0 +1,115 @@
+use crate::core::models::{RetentionPolicy, Snapshot};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use std::collections::HashSet;
+
+/// Maps a snapshot's time to the calendar hour, day, week or month it was taken in.
+type PeriodOf = fn(&DateTime<Local>) -> (i32, u32, u32);
+
+/// Returns the IDs of the snapshots `policy` keeps. Periods are calendar hours, days, ISO weeks
+/// and months in local time. Snapshots whose timestamp cannot be parsed are always kept.
+pub fn snapshots_to_keep(snapshots: &[Snapshot], policy: &RetentionPolicy) -> HashSet<usize> {
+    if policy.is_empty() {
+        return snapshots.iter().map(|snapshot| snapshot.id).collect();
+    }
+
+    let mut keep = HashSet::new();
+    let mut dated = Vec::new();
+    for snapshot in snapshots {
+        if snapshot.tags.iter().any(|tag| policy.keep_tags.contains(tag)) {
+            keep.insert(snapshot.id);
+        }
+        match DateTime::parse_from_rfc3339(&snapshot.timestamp) {
+            Ok(time) => dated.push((time.with_timezone(&Local), snapshot.id)),
+            Err(_) => {
+                keep.insert(snapshot.id);
+            }
+        }
+    }
+    // Newest first, so the snapshot kept for each period is the last one taken in it
+    dated.sort_by(|a, b| b.cmp(a));
+
+    keep.extend(dated.iter().take(policy.keep_last).map(|(_, id)| *id));
+
+    let rules: [(usize, PeriodOf); 4] = [
+        (policy.keep_hourly, |time| (time.year(), time.ordinal(), time.hour())),
+        (policy.keep_daily, |time| (time.year(), time.ordinal(), 0)),
+        (policy.keep_weekly, |time| (time.iso_week().year(), time.iso_week().week(), 0)),
+        (policy.keep_monthly, |time| (time.year(), time.month(), 0)),
+    ];
+    for (count, period) in rules {
+        let mut last_period = None;
+        let mut kept = 0;
+        for (time, id) in &dated {
+            if kept == count {
+                break;
+            }
+            let current = Some(period(time));
+            if current != last_period {
+                keep.insert(*id);
+                kept += 1;
+                last_period = current;
+            }
+        }
+    }
+
+    keep
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn snapshot(id: usize, time: DateTime<Local>, tags: &[&str]) -> Snapshot {
+        Snapshot {
+            id,
+            timestamp: time.to_rfc3339(),
+            changes: 0,
+            file_states: Vec::new(),
+            message: None,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    fn kept_ids(snapshots: &[Snapshot], policy: &RetentionPolicy) -> Vec<usize> {
+        let mut ids: Vec<usize> = snapshots_to_keep(snapshots, policy).into_iter().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_snapshots_to_keep() {
+        // Every six hours for three days, starting at midnight: IDs 1-12, newest last
+        let start = Local.with_ymd_and_hms(2025, 3, 10, 0, 0, 0).unwrap();
+        let mut snapshots: Vec<Snapshot> = (0..12)
+            .map(|i| snapshot(i + 1, start + Duration::hours(6 * i as i64), &[]))
+            .collect();
+        snapshots[1].tags.push("release".to_string());
+
+        assert_eq!(kept_ids(&snapshots, &RetentionPolicy::default()).len(), 12);
+
+        let policy = RetentionPolicy { keep_last: 2, ..Default::default() };
+        assert_eq!(kept_ids(&snapshots, &policy), vec![11, 12]);
+
+        // The last snapshot of each of the two most recent days
+        let policy = RetentionPolicy { keep_daily: 2, ..Default::default() };
+        assert_eq!(kept_ids(&snapshots, &policy), vec![8, 12]);
+
+        // Rules combine, and tagged snapshots are always kept
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            keep_daily: 5,
+            keep_monthly: 1,
+            keep_tags: vec!["release".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(kept_ids(&snapshots, &policy), vec![2, 4, 8, 12]);
+
+        let policy = RetentionPolicy { keep_hourly: 3, ..Default::default() };
+        assert_eq!(kept_ids(&snapshots, &policy), vec![10, 11, 12]);
+
+        snapshots[0].timestamp = "not a timestamp".to_string();
+        let policy = RetentionPolicy { keep_last: 1, ..Default::default() };
+        assert_eq!(kept_ids(&snapshots, &policy), vec![1, 12]);
+    }
+}
@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+
+pub const SCAN_CACHE_FILE_NAME: &str = "scan_cache.json";
+/// Files modified this recently are hashed but not cached: a write within the same timestamp
+/// tick could otherwise go unnoticed by a later scan.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// What the file system reported about a file when it was hashed. If any of it changes, the
+/// cached hash is no longer trusted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub mtime_ns: u64,
+    pub inode: u64,
+    pub ctime_ns: i64,
+}
+
+impl Fingerprint {
+    pub fn new(metadata: &Metadata) -> io::Result<Self> {
+        let mtime_ns = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        let (inode, ctime_ns) = inode_and_ctime(metadata);
+
+        Ok(Self {
+            size: metadata.len(),
+            mtime_ns,
+            inode,
+            ctime_ns,
+        })
+    }
+
+    fn is_racy(&self) -> bool {
+        let now_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        now_ns.saturating_sub(self.mtime_ns) < RACY_WINDOW.as_nanos() as u64
+    }
+}
+
+#[cfg(unix)]
+fn inode_and_ctime(metadata: &Metadata) -> (u64, i64) {
+    use std::os::unix::fs::MetadataExt;
+    (
+        metadata.ino(),
+        metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec(),
+    )
+}
+
+#[cfg(not(unix))]
+fn inode_and_ctime(_metadata: &Metadata) -> (u64, i64) {
+    (0, 0)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CacheEntry {
+    #[serde(flatten)]
+    fingerprint: Fingerprint,
+    hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CacheFile {
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// Hashes of previously scanned files, stored in `.timemachine/scan_cache.json`, so unchanged
+/// files do not have to be read again. The cache is purely an optimization: a missing or
+/// unreadable cache simply means every file is hashed.
+pub struct ScanCache {
+    path: PathBuf,
+    previous: HashMap<String, CacheEntry>,
+    current: HashMap<String, CacheEntry>,
+}
+
+impl ScanCache {
+    pub fn load(base_path: &Path) -> Self {
+        let path = base_path.join(".timemachine").join(SCAN_CACHE_FILE_NAME);
+        let previous = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<CacheFile>(&content).ok())
+            .unwrap_or_default()
+            .entries;
+
+        Self {
+            path,
+            previous,
+            current: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached hash of `path` if the file still matches `fingerprint`.
+    pub fn lookup(&self, path: &str, fingerprint: &Fingerprint) -> Option<String> {
+        self.previous
+            .get(path)
+            .filter(|entry| &entry.fingerprint == fingerprint)
+            .map(|entry| entry.hash.clone())
+    }
+
+    /// Records the hash of a file seen during the current scan.
+    pub fn record(&mut self, path: String, fingerprint: Fingerprint, hash: String) {
+        if !fingerprint.is_racy() {
+            self.current.insert(path, CacheEntry { fingerprint, hash });
+        }
+    }
+
+    /// Replaces the cache on disk with the files seen during the current scan. The file is
+    /// written to a temporary file first, so concurrent scans never see a partial cache.
+    pub fn save(self) -> io::Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let content = serde_json::to_string(&CacheFile {
+            entries: self.current,
+        })?;
+
+        let mut temp_file = NamedTempFile::new_in(dir)?;
+        temp_file.write_all(content.as_bytes())?;
+        temp_file.persist(&self.path).map_err(|e| e.error)?;
+
+        Ok(())
+    }
+
+    /// Deletes the cache so that the next scan hashes every file again.
+    pub fn clear(base_path: &Path) -> io::Result<()> {
+        match fs::remove_file(base_path.join(".timemachine").join(SCAN_CACHE_FILE_NAME)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_scan_cache() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let base_path = test_dir.path();
+        fs::create_dir_all(base_path.join(".timemachine"))?;
+        let file = base_path.join("file.txt");
+        fs::write(&file, "content")?;
+
+        let mut fingerprint = Fingerprint::new(&fs::metadata(&file)?)?;
+        // Pretend the file was last modified well in the past
+        fingerprint.mtime_ns -= 60_000_000_000;
+
+        let mut cache = ScanCache::load(base_path);
+        assert_eq!(cache.lookup("file.txt", &fingerprint), None);
+        cache.record("file.txt".to_string(), fingerprint.clone(), "abc".to_string());
+        cache.save()?;
+
+        let cache = ScanCache::load(base_path);
+        assert_eq!(cache.lookup("file.txt", &fingerprint), Some("abc".to_string()));
+
+        let changed = Fingerprint { size: 8, ..fingerprint.clone() };
+        assert_eq!(cache.lookup("file.txt", &changed), None);
+
+        // Freshly modified files are not cached
+        let mut cache = ScanCache::load(base_path);
+        cache.record("file.txt".to_string(), Fingerprint::new(&fs::metadata(&file)?)?, "abc".to_string());
+        cache.save()?;
+        assert_eq!(ScanCache::load(base_path).lookup("file.txt", &fingerprint), None);
+
+        ScanCache::clear(base_path)?;
+        ScanCache::clear(base_path)?;
+        assert!(!base_path.join(".timemachine").join(SCAN_CACHE_FILE_NAME).exists());
+
+        Ok(())
+    }
+}
@@ -1,66 +1,337 @@
-use crate::core::models::{FileState, ModifiedFileDetail, Snapshot, SnapshotMetadata};
+use crate::core::models::{FileState, ModifiedFileDetail, RenamedFile, Snapshot, SnapshotComparison, SnapshotMetadata};
 use crate::core::content::ContentStore;
-use std::collections::HashMap;
-use std::fs;
-use std::io::{self, ErrorKind};
-use std::path::Path;
+use crate::core::ignore_rules::IgnoreRules;
+use crate::core::scan_cache::{Fingerprint, ScanCache};
+use crate::core::utils::{compute_file_hash, default_jobs, sync_dir, try_parallel_map};
+use crate::error::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub fn load_all_snapshots(path: &str) -> Result<SnapshotMetadata> {
+    let metadata_dir = Path::new(path).join(".timemachine");
+    let metadata_path = metadata_dir.join("metadata.json");
+    let backup_path = metadata_dir.join("metadata.json.bak");
+
+    let mut metadata = match read_metadata(&metadata_path) {
+        Ok(metadata) => metadata,
+        Err(e) if backup_path.exists() => {
+            let backup = read_metadata(&backup_path).map_err(|_| e)?;
+            eprintln!(
+                "Warning: '{}' is unreadable, using the previous version from '{}'",
+                metadata_path.display(),
+                backup_path.display()
+            );
+            backup
+        }
+        Err(e) => return Err(e),
+    };
+
+    migrate_snapshot_ids(&mut metadata);
 
-pub fn load_all_snapshots(path: &str) -> io::Result<SnapshotMetadata> {
-    let metadata_path = Path::new(path).join(".timemachine/metadata.json");
+    Ok(metadata)
+}
+
+/// Parses `metadata_path` as stored, without falling back to the backup or migrating IDs.
+pub fn read_metadata(metadata_path: &Path) -> Result<SnapshotMetadata> {
     let metadata_content = fs::read_to_string(metadata_path)?;
-    serde_json::from_str(&metadata_content).map_err(|e| {
-        io::Error::new(
-            ErrorKind::InvalidData,
-            format!("Failed to parse metadata: {}", e),
-        )
+    serde_json::from_str(&metadata_content).map_err(|e| Error::CorruptMetadata {
+        path: metadata_path.to_path_buf(),
+        reason: e.to_string(),
     })
 }
 
-pub fn collect_file_states(dir: &str) -> Result<Vec<FileState>, io::Error> {
+/// Writes metadata without ever leaving a partially written `metadata.json` behind.
+///
+/// The new content goes to a temporary file which is flushed to disk and then renamed over
+/// the original. The previous generation is kept as `metadata.json.bak` (unless it is itself
+/// corrupt) so that `load_all_snapshots` can fall back to it.
+pub fn save_metadata(path: &str, metadata: &SnapshotMetadata) -> io::Result<()> {
+    let metadata_dir = Path::new(path).join(".timemachine");
+    let metadata_path = metadata_dir.join("metadata.json");
+    let backup_path = metadata_dir.join("metadata.json.bak");
+    let temp_path = metadata_dir.join("metadata.json.tmp");
+
+    let content = serde_json::to_string_pretty(metadata)?;
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(content.as_bytes())?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+
+    if read_metadata(&metadata_path).is_ok() {
+        fs::copy(&metadata_path, &backup_path)?;
+        File::open(&backup_path)?.sync_all()?;
+    }
+
+    fs::rename(&temp_path, &metadata_path)?;
+    sync_dir(&metadata_dir)?;
+
+    Ok(())
+}
+
+/// Brings metadata written by older versions up to date: snapshots that reused an existing
+/// ID are given fresh ones, and `next_id` is set past the highest ID in use.
+/// The result is deterministic, so it is persisted by the next command that saves metadata.
+pub fn migrate_snapshot_ids(metadata: &mut SnapshotMetadata) {
+    let mut max_id = metadata.snapshots.iter().map(|s| s.id).max().unwrap_or(0);
+    let mut seen = HashSet::new();
+
+    for snapshot in &mut metadata.snapshots {
+        if !seen.insert(snapshot.id) {
+            max_id += 1;
+            eprintln!(
+                "Warning: duplicate snapshot ID {} found, renumbering it to {}",
+                snapshot.id, max_id
+            );
+            snapshot.id = max_id;
+            seen.insert(max_id);
+        }
+    }
+
+    metadata.next_id = metadata.next_id.max(max_id + 1);
+}
+
+/// A file found by `walk_dir`, not hashed yet.
+struct ScannedFile {
+    relative: String,
+    path: PathBuf,
+    modified_secs: u64,
+    fingerprint: Fingerprint,
+}
+
+/// Scans `dir` and hashes every tracked file on all available cores.
+/// See `collect_file_states_with_jobs`.
+pub fn collect_file_states(dir: &str) -> io::Result<Vec<FileState>> {
+    collect_file_states_with_jobs(dir, default_jobs())
+}
+
+/// Scans `dir` and hashes every tracked file, using up to `jobs` threads. Nothing is written to
+/// the content store, so it is safe to use for inspection commands such as status and diff; the
+/// only write is the updated scan cache in `.timemachine/scan_cache.json`, if the repository
+/// exists. Files whose size, timestamps and inode match the scan cache are not read again. The
+/// result is sorted by path regardless of `jobs`. Only file content in flight is bounded: at
+/// most `jobs` files are read at a time, each streamed through a fixed-size buffer. The path
+/// list is not, since the whole tree is listed before hashing starts, so memory use still grows
+/// linearly with the number of files. Hashing stops at the first file that cannot be read.
+pub fn collect_file_states_with_jobs(dir: &str, jobs: usize) -> io::Result<Vec<FileState>> {
     let base_path = Path::new(&dir);
-    let mut file_states = Vec::new();
     let metadata_dir = base_path.join(".timemachine");
+    let rules = IgnoreRules::load(base_path)?;
+    let mut cache = ScanCache::load(base_path);
+
+    let mut files = Vec::new();
+    walk_dir(base_path, base_path, &metadata_dir, &rules, &mut files)?;
+
+    let hashes = try_parallel_map(&files, jobs, |file| match cache.lookup(&file.relative, &file.fingerprint) {
+        Some(hash) => Ok(hash),
+        None => compute_file_hash(&file.path),
+    })?;
+
+    let mut file_states = Vec::with_capacity(files.len());
+    for (file, hash) in files.into_iter().zip(hashes) {
+        let size = file.fingerprint.size;
+        cache.record(file.relative.clone(), file.fingerprint, hash.clone());
+
+        file_states.push(FileState {
+            path: file.relative,
+            size,
+            last_modified: file.modified_secs.to_string(),
+            hash,
+        });
+    }
+
+    // The cache only saves work, so failing to write it (e.g. on a read-only mount) is not an error
+    if metadata_dir.is_dir() {
+        cache.save().ok();
+    }
+
+    Ok(file_states)
+}
+
+/// Copies the content of each scanned file into the content store, compressing up to `jobs`
+/// files at a time. If a file changed after it was scanned, its state is updated to match the
+/// content that was actually stored. Blobs that are already present are reused unless they are
+/// incomplete (e.g. truncated by a crash), which only takes reading their frame headers.
+pub fn store_file_states(dir: &str, file_states: &mut [FileState], jobs: usize) -> io::Result<()> {
+    let base_path = Path::new(dir);
     let store = ContentStore::new(base_path);
     store.init()?;
 
-    for entry in fs::read_dir(base_path)? {
-        let entry = entry?;
+    loop {
+        // Store each missing or incomplete hash once; duplicates are picked up by the next round
+        // if the file that was stored turned out to have changed
+        let mut seen = HashSet::new();
+        let pending: Vec<usize> = file_states
+            .iter()
+            .enumerate()
+            .filter(|(_, state)| !store.is_complete(&state.hash) && seen.insert(state.hash.as_str()))
+            .map(|(index, _)| index)
+            .collect();
+        if pending.is_empty() {
+            // Make the renamed blobs durable before metadata starts referring to them
+            return store.sync();
+        }
+
+        let stored = try_parallel_map(&pending, jobs, |&index| {
+            store.store_file(&base_path.join(&file_states[index].path))
+        })?;
+
+        for (index, (hash, size)) in pending.into_iter().zip(stored) {
+            if hash != file_states[index].hash {
+                file_states[index].hash = hash;
+                file_states[index].size = size;
+            }
+        }
+    }
+}
+
+/// Recursively lists every file below `current`, skipping the `.timemachine` folder and
+/// anything excluded by `.timemachineignore`. Symlinked directories are not followed to avoid cycles.
+fn walk_dir(
+    base_path: &Path,
+    current: &Path,
+    metadata_dir: &Path,
+    rules: &IgnoreRules,
+    files: &mut Vec<ScannedFile>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(current)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
         let path = entry.path();
 
-        if path.is_dir() || path.starts_with(&metadata_dir) {
+        if path.starts_with(metadata_dir) {
+            continue;
+        }
+
+        let relative = relative_path(base_path, &path)?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !rules.matches(&relative, true)? {
+                walk_dir(base_path, &path, metadata_dir, rules, files)?;
+            }
+            continue;
+        }
+
+        if rules.matches(&relative, false)? {
             continue;
         }
 
+        // Follows symlinks; symlinked directories and special files are not recorded
         let metadata = fs::metadata(&path)?;
-        let modified_time = metadata
+        if !metadata.is_file() {
+            continue;
+        }
+        let modified_secs = metadata
             .modified()?
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
 
-        let hash = store.store_file(&path)?;
+        files.push(ScannedFile {
+            relative,
+            path,
+            modified_secs,
+            fingerprint: Fingerprint::new(&metadata)?,
+        });
+    }
 
-        let file_state = FileState {
-            path: path
-                .strip_prefix(base_path)
-                .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?
-                .to_string_lossy()
-                .to_string(),
-            size: metadata.len(),
-            last_modified: modified_time.to_string(),
-            hash,
-        };
+    Ok(())
+}
 
-        file_states.push(file_state);
+/// Converts `path` into a `/`-separated path relative to `base_path`, regardless of platform.
+pub fn relative_path(base_path: &Path, path: &Path) -> io::Result<String> {
+    let relative = path
+        .strip_prefix(base_path)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    Ok(relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/"))
+}
+
+/// Normalizes a user-supplied path to the form stored in `FileState.path`: `/`-separated,
+/// without a leading `./` or surrounding slashes.
+pub fn normalize_path(path: &str) -> String {
+    path.replace('\\', "/")
+        .trim_start_matches("./")
+        .trim_matches('/')
+        .to_string()
+}
+
+/// Compares two sets of file states by hash and size; timestamps alone never count as a change.
+/// Files whose content moved to another path are reported as renames.
+pub fn compare_file_maps(old_map: &HashMap<String, &FileState>, new_map: &HashMap<String, &FileState>) -> SnapshotComparison {
+    let mut new_files = find_new_files(new_map, old_map);
+    let mut deleted_files = find_deleted_files(old_map, new_map);
+    let renamed_files = find_renamed_files(old_map, new_map, &mut new_files, &mut deleted_files);
+
+    SnapshotComparison {
+        new_files,
+        modified_files: find_modified_files(old_map, new_map),
+        deleted_files,
+        renamed_files,
     }
+}
 
-    Ok(file_states)
+/// Like `compare_file_maps`, with `snapshot_states` as the old side, but leaves out recorded
+/// files that the current ignore rules exclude.
+pub fn compare_with_working_tree(dir: &str, snapshot_states: &[FileState], current_states: &[FileState]) -> io::Result<SnapshotComparison> {
+    let current_map = create_file_map(current_states);
+    let mut snapshot_map = create_file_map(snapshot_states);
+    remove_ignored(dir, &mut snapshot_map)?;
+
+    Ok(compare_file_maps(&snapshot_map, &current_map))
+}
+
+/// Drops entries that the current `.timemachineignore` rules exclude, so that ignored files
+/// recorded by older snapshots are neither reported nor touched.
+pub fn remove_ignored(dir: &str, file_map: &mut HashMap<String, &FileState>) -> io::Result<()> {
+    let rules = IgnoreRules::load(Path::new(dir))?;
+
+    let mut ignored = Vec::new();
+    for path in file_map.keys() {
+        if rules.is_ignored(path, false)? {
+            ignored.push(path.clone());
+        }
+    }
+    for path in ignored {
+        file_map.remove(&path);
+    }
+
+    Ok(())
 }
 
 pub fn find_snapshot(metadata: &SnapshotMetadata, snapshot_id: usize) -> Option<&Snapshot> {
     metadata.snapshots.iter().find(|s| s.id == snapshot_id)
 }
 
+/// Like `find_snapshot`, but reports a missing snapshot along with the IDs that do exist.
+pub fn get_snapshot(metadata: &SnapshotMetadata, snapshot_id: usize) -> Result<&Snapshot> {
+    find_snapshot(metadata, snapshot_id).ok_or_else(|| snapshot_not_found(metadata, snapshot_id))
+}
+
+pub fn snapshot_not_found(metadata: &SnapshotMetadata, snapshot_id: usize) -> Error {
+    Error::SnapshotNotFound {
+        id: snapshot_id,
+        available: metadata.snapshots.iter().map(|s| s.id).collect(),
+    }
+}
+
+/// Finds a snapshot by numeric ID or, failing that, by tag name.
+pub fn find_snapshot_by_ref<'a>(metadata: &'a SnapshotMetadata, reference: &str) -> Option<&'a Snapshot> {
+    match reference.parse::<usize>() {
+        Ok(id) => find_snapshot(metadata, id),
+        Err(_) => metadata
+            .snapshots
+            .iter()
+            .find(|s| s.tags.iter().any(|tag| tag == reference)),
+    }
+}
+
 pub fn create_file_map(file_states: &[FileState]) -> HashMap<String, &FileState> {
     file_states.iter().map(|fs| (fs.path.clone(), fs)).collect()
 }
@@ -69,18 +340,20 @@ pub fn find_new_files(
     new_snapshot: &HashMap<String, &FileState>,
     old_snapshot: &HashMap<String, &FileState>,
 ) -> Vec<String> {
-    new_snapshot
+    let mut new_files: Vec<String> = new_snapshot
         .keys()
         .filter(|path| !old_snapshot.contains_key(*path))
         .cloned()
-        .collect()
+        .collect();
+    new_files.sort();
+    new_files
 }
 
 pub fn find_modified_files(
     old_snapshot: &HashMap<String, &FileState>,
     new_snapshot: &HashMap<String, &FileState>,
 ) -> Vec<ModifiedFileDetail> {
-    new_snapshot
+    let mut modified_files: Vec<ModifiedFileDetail> = new_snapshot
         .iter()
         .filter_map(|(path, new_file)| {
             if let Some(old_file) = old_snapshot.get(path) {
@@ -98,18 +371,58 @@ pub fn find_modified_files(
             }
             None
         })
-        .collect()
+        .collect();
+    modified_files.sort_by(|a, b| a.path.cmp(&b.path));
+    modified_files
 }
 
 pub fn find_deleted_files(
     old_snapshot: &HashMap<String, &FileState>,
     new_snapshot: &HashMap<String, &FileState>,
 ) -> Vec<String> {
-    old_snapshot
+    let mut deleted_files: Vec<String> = old_snapshot
         .keys()
         .filter(|path| !new_snapshot.contains_key(*path))
         .cloned()
-        .collect()
+        .collect();
+    deleted_files.sort();
+    deleted_files
+}
+
+/// Pairs entries of `deleted_files` and `new_files` whose content is identical as renames and
+/// removes them from both lists. Candidates sharing a hash are paired in path order.
+pub fn find_renamed_files(
+    old_snapshot: &HashMap<String, &FileState>,
+    new_snapshot: &HashMap<String, &FileState>,
+    new_files: &mut Vec<String>,
+    deleted_files: &mut Vec<String>,
+) -> Vec<RenamedFile> {
+    let mut deleted_by_hash: HashMap<&str, Vec<&String>> = HashMap::new();
+    for path in deleted_files.iter() {
+        deleted_by_hash.entry(old_snapshot[path].hash.as_str()).or_default().push(path);
+    }
+    // Paths are sorted, so popping from the reversed list yields them in path order
+    for candidates in deleted_by_hash.values_mut() {
+        candidates.reverse();
+    }
+
+    let mut renamed_files = Vec::new();
+    for path in new_files.iter() {
+        let new_file = new_snapshot[path];
+        if let Some(old_path) = deleted_by_hash.get_mut(new_file.hash.as_str()).and_then(Vec::pop) {
+            renamed_files.push(RenamedFile {
+                old_path: old_path.clone(),
+                new_path: path.clone(),
+                similarity: 100,
+            });
+        }
+    }
+
+    let new_paths: HashSet<&str> = renamed_files.iter().map(|r| r.new_path.as_str()).collect();
+    let old_paths: HashSet<&str> = renamed_files.iter().map(|r| r.old_path.as_str()).collect();
+    new_files.retain(|path| !new_paths.contains(path.as_str()));
+    deleted_files.retain(|path| !old_paths.contains(path.as_str()));
+    renamed_files
 }
 
 #[cfg(test)]
@@ -136,6 +449,94 @@ mod tests {
         assert_eq!(file_states[0].path, "file1.txt");
     }
 
+    #[test]
+    fn test_collect_file_states_is_read_only() -> Result<()> {
+        let test_dir = tempdir()?;
+        let test_path = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(test_path)?;
+        fs::write(Path::new(test_path).join("file.txt"), "content")?;
+
+        let mut file_states = collect_file_states(test_path)?;
+        let contents_dir = Path::new(test_path).join(".timemachine").join("contents");
+        assert!(!contents_dir.exists() || fs::read_dir(&contents_dir)?.next().is_none());
+
+        store_file_states(test_path, &mut file_states, 4)?;
+        assert!(contents_dir.join(&file_states[0].hash).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_file_states_recursive() {
+        let test_dir = tempdir().unwrap();
+        let test_path = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(test_path).unwrap();
+
+        let nested_dir = Path::new(test_path).join("src").join("core");
+        fs::create_dir_all(&nested_dir).unwrap();
+        fs::write(Path::new(test_path).join("top.txt"), "top").unwrap();
+        fs::write(nested_dir.join("deep.txt"), "deep").unwrap();
+        fs::create_dir_all(Path::new(test_path).join("empty")).unwrap();
+
+        let file_states = collect_file_states(test_path).unwrap();
+        let paths: Vec<&str> = file_states.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(paths, vec!["src/core/deep.txt", "top.txt"]);
+    }
+
+    #[test]
+    fn test_collect_file_states_respects_ignore_file() {
+        let test_dir = tempdir().unwrap();
+        let test_path = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(test_path).unwrap();
+
+        let base_path = Path::new(test_path);
+        fs::write(base_path.join(".timemachineignore"), "target/\n*.swp\n").unwrap();
+        fs::create_dir_all(base_path.join("target").join("debug")).unwrap();
+        fs::write(base_path.join("target").join("debug").join("app"), "binary").unwrap();
+        fs::write(base_path.join("main.rs.swp"), "swap").unwrap();
+        fs::write(base_path.join("main.rs"), "fn main() {}").unwrap();
+
+        let file_states = collect_file_states(test_path).unwrap();
+        let paths: Vec<&str> = file_states.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(paths, vec![".timemachineignore", "main.rs"]);
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("./src/main.rs"), "src/main.rs");
+        assert_eq!(normalize_path("docs/"), "docs");
+        assert_eq!(normalize_path("src\\core\\mod.rs"), "src/core/mod.rs");
+    }
+
+    #[test]
+    fn test_find_renamed_files() {
+        let state = |path: &str, hash: &str| FileState {
+            path: path.to_string(),
+            size: 1,
+            last_modified: "0".to_string(),
+            hash: hash.to_string(),
+        };
+        let old_states = vec![state("a.txt", "h1"), state("b.txt", "h2"), state("c.txt", "h2")];
+        let new_states = vec![state("docs/a.txt", "h1"), state("d.txt", "h2"), state("e.txt", "h3")];
+        let old_map = create_file_map(&old_states);
+        let new_map = create_file_map(&new_states);
+
+        let mut new_files = find_new_files(&new_map, &old_map);
+        let mut deleted_files = find_deleted_files(&old_map, &new_map);
+        let renamed = find_renamed_files(&old_map, &new_map, &mut new_files, &mut deleted_files);
+
+        let pairs: Vec<_> = renamed
+            .iter()
+            .map(|r| (r.old_path.as_str(), r.new_path.as_str()))
+            .collect();
+        assert_eq!(pairs, vec![("b.txt", "d.txt"), ("a.txt", "docs/a.txt")]);
+        assert_eq!(new_files, vec!["e.txt"]);
+        assert_eq!(deleted_files, vec!["c.txt"]);
+    }
+
     #[test]
     fn test_load_snapshots() {
         let test_dir = tempdir().unwrap();
@@ -143,7 +544,7 @@ mod tests {
 
         initialize_timemachine(test_path).unwrap();
 
-        let metadata = SnapshotMetadata { snapshots: vec![] };
+        let metadata = SnapshotMetadata { snapshots: vec![], next_id: 1 };
         let metadata_path = Path::new(test_path).join(".timemachine/metadata.json");
         fs::write(&metadata_path, serde_json::to_string(&metadata).unwrap()).unwrap();
 
@@ -152,6 +553,33 @@ mod tests {
         assert_eq!(result.unwrap().snapshots.len(), 0);
     }
 
+    #[test]
+    fn test_save_metadata_keeps_backup() {
+        let test_dir = tempdir().unwrap();
+        let test_path = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(test_path).unwrap();
+
+        let mut metadata = load_all_snapshots(test_path).unwrap();
+        metadata.next_id = 5;
+        save_metadata(test_path, &metadata).unwrap();
+        metadata.next_id = 6;
+        save_metadata(test_path, &metadata).unwrap();
+
+        let metadata_dir = Path::new(test_path).join(".timemachine");
+        assert!(!metadata_dir.join("metadata.json.tmp").exists());
+        assert_eq!(read_metadata(&metadata_dir.join("metadata.json.bak")).unwrap().next_id, 5);
+
+        // A corrupt primary falls back to the previous generation
+        fs::write(metadata_dir.join("metadata.json"), "{\"snapshots\": [").unwrap();
+        assert_eq!(load_all_snapshots(test_path).unwrap().next_id, 5);
+
+        // Saving again does not overwrite the good backup with the corrupt primary
+        save_metadata(test_path, &metadata).unwrap();
+        assert_eq!(read_metadata(&metadata_dir.join("metadata.json.bak")).unwrap().next_id, 5);
+        assert_eq!(load_all_snapshots(test_path).unwrap().next_id, 6);
+    }
+
     #[test]
     fn test_find_snapshot() {
         let metadata = SnapshotMetadata {
@@ -161,14 +589,19 @@ mod tests {
                     timestamp: "2024-01-01T12:00:00Z".to_string(),
                     changes: 0,
                     file_states: vec![],
+                    message: None,
+                    tags: vec![],
                 },
                 Snapshot {
                     id: 2,
                     timestamp: "2024-01-02T12:00:00Z".to_string(),
                     changes: 0,
                     file_states: vec![],
+                    message: None,
+                    tags: vec![],
                 },
             ],
+            next_id: 3,
         };
 
         let result = find_snapshot(&metadata, 2);
@@ -179,6 +612,58 @@ mod tests {
         assert!(not_found.is_none());
     }
 
+    #[test]
+    fn test_find_snapshot_by_ref() {
+        let metadata = SnapshotMetadata {
+            snapshots: vec![Snapshot {
+                id: 1,
+                timestamp: "2024-01-01T12:00:00Z".to_string(),
+                changes: 0,
+                file_states: vec![],
+                message: Some("first".to_string()),
+                tags: vec!["v1".to_string()],
+            }],
+            next_id: 2,
+        };
+
+        assert_eq!(find_snapshot_by_ref(&metadata, "1").unwrap().id, 1);
+        assert_eq!(find_snapshot_by_ref(&metadata, "v1").unwrap().id, 1);
+        assert!(find_snapshot_by_ref(&metadata, "v2").is_none());
+        assert!(find_snapshot_by_ref(&metadata, "2").is_none());
+    }
+
+    #[test]
+    fn test_migrate_snapshot_ids() {
+        let snapshot = |id: usize| Snapshot {
+            id,
+            timestamp: "2024-01-01T12:00:00Z".to_string(),
+            changes: 0,
+            file_states: vec![],
+            message: None,
+            tags: vec![],
+        };
+
+        // Snapshot 2 of 3 was deleted and the next snapshot reused ID 3
+        let mut metadata = SnapshotMetadata {
+            snapshots: vec![snapshot(1), snapshot(3), snapshot(3)],
+            next_id: 0,
+        };
+
+        migrate_snapshot_ids(&mut metadata);
+
+        let ids: Vec<usize> = metadata.snapshots.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![1, 3, 4]);
+        assert_eq!(metadata.next_id, 5);
+
+        // An up-to-date counter is left alone
+        let mut metadata = SnapshotMetadata {
+            snapshots: vec![snapshot(1)],
+            next_id: 7,
+        };
+        migrate_snapshot_ids(&mut metadata);
+        assert_eq!(metadata.next_id, 7);
+    }
+
     #[test]
     fn test_create_file_map() {
         let file_states = vec![
@@ -1,7 +1,11 @@
 use sha2::{Digest, Sha256};
 use std::fs;
-use std::io;
+use std::io::{self, Read};
+use std::num::NonZeroUsize;
+use std::panic;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
 
 pub fn compute_file_hash(path: &Path) -> io::Result<String> {
     let mut file = fs::File::open(path).map_err(|e| {
@@ -13,16 +17,127 @@ pub fn compute_file_hash(path: &Path) -> io::Result<String> {
 
     let mut hasher = Sha256::new();
     io::copy(&mut file, &mut hasher).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to read file for hashing: {}", e),
-        )
+        io::Error::other(format!("Failed to read file for hashing: {}", e))
     })?;
 
     let result = hasher.finalize();
     Ok(format!("{:x}", result))
 }
 
+/// Flushes a directory entry so that a preceding rename survives a crash.
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Wraps a reader and computes the SHA-256 of everything read through it, so content can be
+/// hashed while it is being processed in a single pass.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Returns the hex-encoded hash and the number of the bytes read so far.
+    pub fn finish(self) -> (String, u64) {
+        (format!("{:x}", self.hasher.finalize()), self.len)
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.len += read as u64;
+        Ok(read)
+    }
+}
+
+/// Number of worker threads used when the caller does not ask for a specific number.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Applies `f` to every item on up to `jobs` threads and returns the results in input order.
+/// Workers pick up the next unprocessed item one at a time, so no more than `jobs` items are
+/// in flight at once regardless of how many there are.
+pub fn parallel_map<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let jobs = jobs.clamp(1, items.len().max(1));
+    if jobs == 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else { break };
+                        done.push((index, f(item)));
+                    }
+                    done
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            let done = worker.join().unwrap_or_else(|e| panic::resume_unwind(e));
+            for (index, result) in done {
+                results[index] = Some(result);
+            }
+        }
+    });
+
+    results.into_iter().map(|result| result.expect("every item is processed")).collect()
+}
+
+/// Like `parallel_map` for work that can fail, but stops picking up new items as soon as one
+/// has failed and returns an error from one of the failed items.
+pub fn try_parallel_map<T, R, E, F>(items: &[T], jobs: usize, f: F) -> Result<Vec<R>, E>
+where
+    T: Sync,
+    R: Send,
+    E: Send,
+    F: Fn(&T) -> Result<R, E> + Sync,
+{
+    let failed = AtomicBool::new(false);
+    let results = parallel_map(items, jobs, |item| {
+        if failed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let result = f(item);
+        if result.is_err() {
+            failed.store(true, Ordering::Relaxed);
+        }
+        Some(result)
+    });
+
+    results.into_iter().flatten().collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -43,4 +158,44 @@ mod tests {
             "d9014c4624844aa5bac314773d6b689ad467fa4e1d1a50a1b8a99d5a95f72ff5"
         );
     }
+
+    #[test]
+    fn test_hashing_reader() -> io::Result<()> {
+        let mut reader = HashingReader::new(&b"Hello, world!\n"[..]);
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content)?;
+        assert_eq!(content, b"Hello, world!\n");
+        assert_eq!(
+            reader.finish(),
+            ("d9014c4624844aa5bac314773d6b689ad467fa4e1d1a50a1b8a99d5a95f72ff5".to_string(), 14)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_map() {
+        let items: Vec<usize> = (0..1000).collect();
+        for jobs in [0, 1, 4, 5000] {
+            let squares = parallel_map(&items, jobs, |n| n * n);
+            assert_eq!(squares, items.iter().map(|n| n * n).collect::<Vec<_>>());
+        }
+        assert!(parallel_map(&[] as &[usize], 4, |n| *n).is_empty());
+    }
+
+    #[test]
+    fn test_try_parallel_map() {
+        let items: Vec<usize> = (0..1000).collect();
+        assert_eq!(try_parallel_map(&items, 4, |n| Ok::<_, ()>(n + 1)).unwrap()[999], 1000);
+
+        // Items after a failure are not processed
+        for jobs in [1, 4] {
+            let calls = AtomicUsize::new(0);
+            let result = try_parallel_map(&items, jobs, |&n| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                if n == 10 { Err(n) } else { Ok(n) }
+            });
+            assert_eq!(result, Err(10));
+            assert!(calls.load(Ordering::Relaxed) < 100);
+        }
+    }
 }
@@ -0,0 +1,140 @@
+use crate::core::content::ContentStore;
+use crate::core::models::{BlobStatus, DamagedBlob, VerifyReport};
+use crate::core::snapshot::read_metadata;
+use crate::error::{Error, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Checks that the metadata of `dir` parses, that snapshot IDs are unique, and that every blob
+/// referenced by any snapshot exists and decodes to its hash. With `sample_percent`, all blobs
+/// are still checked for existence but only roughly that share of them is decompressed; a
+/// different share is picked on every run. Blobs are decompressed on up to `jobs` threads.
+pub fn check_repository(dir: &str, sample_percent: Option<u8>, jobs: usize) -> Result<VerifyReport> {
+    let base_path = Path::new(dir);
+    let metadata_dir = base_path.join(".timemachine");
+    let metadata_path = metadata_dir.join("metadata.json");
+    let backup_path = metadata_dir.join("metadata.json.bak");
+
+    // Read both files as stored: the backup fallback and ID migration done by
+    // load_all_snapshots would hide the problems we are looking for
+    let (metadata, metadata_error) = match read_metadata(&metadata_path) {
+        Ok(metadata) => (Some(metadata), None),
+        Err(e) => {
+            let reason = match e {
+                Error::CorruptMetadata { reason, .. } => reason,
+                Error::Io(e) if e.kind() == ErrorKind::NotFound => {
+                    format!("'{}' does not exist", metadata_path.display())
+                }
+                e => return Err(e),
+            };
+            match read_metadata(&backup_path) {
+                Ok(backup) => (Some(backup), Some(format!("{}; checked the backup instead", reason))),
+                Err(_) => (None, Some(format!("{}; no usable backup, only stored contents were checked", reason))),
+            }
+        }
+    };
+    let snapshots = metadata.as_ref().map_or(&[][..], |metadata| &metadata.snapshots);
+
+    let mut id_counts: HashMap<usize, usize> = HashMap::new();
+    for snapshot in snapshots {
+        *id_counts.entry(snapshot.id).or_default() += 1;
+    }
+    let mut duplicate_ids: Vec<usize> = id_counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(id, _)| id)
+        .collect();
+    duplicate_ids.sort();
+
+    let mut references: BTreeMap<&str, DamagedBlob> = BTreeMap::new();
+    for snapshot in snapshots {
+        for state in &snapshot.file_states {
+            let blob = references.entry(state.hash.as_str()).or_insert_with(|| DamagedBlob {
+                hash: state.hash.clone(),
+                snapshot_ids: Vec::new(),
+                paths: Vec::new(),
+            });
+            if !blob.snapshot_ids.contains(&snapshot.id) {
+                blob.snapshot_ids.push(snapshot.id);
+            }
+            if !blob.paths.contains(&state.path) {
+                blob.paths.push(state.path.clone());
+            }
+        }
+    }
+
+    let store = ContentStore::new(base_path);
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as u64);
+    let blobs_referenced = references.len();
+    let mut missing_blobs = Vec::new();
+    let mut sampled = Vec::new();
+    for (hash, blob) in references {
+        if !store.contains(hash) {
+            missing_blobs.push(blob);
+        } else if is_sampled(hash, sample_percent, seed) {
+            sampled.push(blob);
+        }
+    }
+
+    let hashes: Vec<&str> = sampled.iter().map(|blob| blob.hash.as_str()).collect();
+    let verification = store.verify_all(&hashes, jobs);
+    let corrupt_blobs: Vec<DamagedBlob> = sampled
+        .into_iter()
+        .zip(&verification.blobs)
+        .filter(|(_, check)| check.status != BlobStatus::Ok)
+        .map(|(blob, _)| blob)
+        .collect();
+
+    // Without any metadata every blob would look orphaned
+    let mut orphaned_blobs = match &metadata {
+        Some(metadata) => store.find_orphaned_content(metadata)?,
+        None => Vec::new(),
+    };
+    orphaned_blobs.sort();
+
+    Ok(VerifyReport {
+        metadata_error,
+        snapshots_checked: snapshots.len(),
+        duplicate_ids,
+        blobs_referenced,
+        blobs_verified: verification.blobs.len(),
+        bytes_verified: verification.bytes_verified,
+        bytes_per_second: verification.bytes_per_second,
+        missing_blobs,
+        corrupt_blobs,
+        orphaned_blobs,
+    })
+}
+
+/// Hashes are uniformly distributed, so their leading digits shifted by a per-run `seed`
+/// select about `sample_percent` percent of the blobs, and a different set on each run.
+fn is_sampled(hash: &str, sample_percent: Option<u8>, seed: u64) -> bool {
+    match sample_percent {
+        None => true,
+        Some(percent) => {
+            let prefix = u64::from_str_radix(hash.get(..8).unwrap_or(hash), 16).unwrap_or(0);
+            (prefix + seed) % 100 < u64::from(percent)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sampled() {
+        let hashes: Vec<String> = (0..1000u32).map(|i| format!("{:08x}", i * 4_294_967)).collect();
+        let count = |percent, seed| hashes.iter().filter(|h| is_sampled(h, percent, seed)).count();
+
+        assert_eq!(count(None, 0), 1000);
+        assert_eq!(count(Some(100), 7), 1000);
+        assert_eq!(count(Some(0), 7), 0);
+        let sampled = count(Some(10), 42);
+        assert!((50..150).contains(&sampled), "sampled {} of 1000", sampled);
+    }
+}
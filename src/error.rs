@@ -0,0 +1,122 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors returned by the TimeMachine library.
+///
+/// Callers can match on the variant instead of inspecting messages. Failures that do not
+/// need special handling are wrapped in [`Error::Io`].
+#[derive(Debug)]
+pub enum Error {
+    SnapshotNotFound { id: usize, available: Vec<usize> },
+    TagNotFound { tag: String },
+    PathNotFound { id: usize, path: String },
+    TagExists { tag: String, id: usize },
+    InvalidTag { tag: String },
+    UncommittedChanges,
+    TargetNotEmpty { path: PathBuf },
+    TargetInsideRepository { path: PathBuf },
+    InsufficientSpace { required: u64, available: u64 },
+    CorruptMetadata { path: PathBuf, reason: String },
+    ContentMissing { hash: String },
+    Locked { pid: u32 },
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::SnapshotNotFound { id, available } => write!(
+                f,
+                "Snapshot {} does not exist. Available snapshots: {}",
+                id,
+                available
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Error::TagNotFound { tag } => write!(f, "Tag '{}' not found", tag),
+            Error::PathNotFound { id, path } => {
+                write!(f, "Path '{}' not found in snapshot {}", path, id)
+            }
+            Error::TagExists { tag, id } => {
+                write!(f, "Tag '{}' is already used by snapshot {}", tag, id)
+            }
+            Error::InvalidTag { tag } => write!(
+                f,
+                "Invalid tag '{}': tags must be non-empty and not purely numeric",
+                tag
+            ),
+            Error::UncommittedChanges => write!(
+                f,
+                "Uncommitted changes detected. Take another snapshot before proceeding to restore, or use --force to override (this will automatically create a backup of your current state)."
+            ),
+            Error::TargetNotEmpty { path } => write!(
+                f,
+                "Target directory '{}' is not empty",
+                path.display()
+            ),
+            Error::TargetInsideRepository { path } => write!(
+                f,
+                "Target directory '{}' is inside the tracked directory; choose a location outside it or add it to .timemachineignore",
+                path.display()
+            ),
+            Error::InsufficientSpace { required, available } => write!(
+                f,
+                "Insufficient disk space for restoration: {} bytes required, {} bytes available",
+                required, available
+            ),
+            Error::CorruptMetadata { path, reason } => write!(
+                f,
+                "Failed to parse metadata '{}': {}",
+                path.display(),
+                reason
+            ),
+            Error::ContentMissing { hash } => write!(f, "Content not found for hash: {}", hash),
+            Error::Locked { pid } => write!(f, "Repository is locked by process {}", pid),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+/// Allows library errors to flow into code that works with `io::Result`.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::Io(inner) => return inner,
+            Error::SnapshotNotFound { .. } | Error::TagNotFound { .. } => io::ErrorKind::NotFound,
+            Error::PathNotFound { .. } => io::ErrorKind::NotFound,
+            Error::ContentMissing { .. } => io::ErrorKind::NotFound,
+            Error::TagExists { .. } | Error::TargetNotEmpty { .. } => io::ErrorKind::AlreadyExists,
+            Error::InvalidTag { .. } | Error::TargetInsideRepository { .. } => io::ErrorKind::InvalidInput,
+            Error::CorruptMetadata { .. } => io::ErrorKind::InvalidData,
+            Error::Locked { .. } => io::ErrorKind::WouldBlock,
+            Error::UncommittedChanges | Error::InsufficientSpace { .. } => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e.to_string())
+    }
+}
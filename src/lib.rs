@@ -1,19 +1,30 @@
 mod core;
+mod error;
+
+pub use core::models::{BlobCheck, BlobStatus, BlobVerification, ChangeStatus, DamagedBlob, FileDiff, GcReport, ModifiedFileDetail, PruneReport, RenamedFile, RestoreReport, RetentionPolicy, SnapshotComparison, SnapshotOptions, SnapshotListInfo, StatusInfo, VerifyReport};
+pub use error::{Error, Result};
 
 use chrono::prelude::*;
 
-use std::io::ErrorKind;
 use std::path::Path;
-use std::{fs, io};
-use std::collections::HashSet;
-use core::models::{Snapshot, SnapshotComparison, SnapshotMetadata, RestoreReport};
-use core::snapshot::{collect_file_states, create_file_map, find_deleted_files, find_modified_files, find_new_files, find_snapshot, load_all_snapshots};
-use core::restore::{validate_permissions,generate_restore_report, has_available_space, has_uncommitted_changes, perform_restore};
-use sysinfo::{DiskRefreshKind, Disks};
+use std::fs;
+use std::io::Read;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+use core::models::{FileState, Snapshot, SnapshotMetadata};
+use core::patch::{diff_file, diff_renamed_file, find_similar_renames, read_text, FileSource};
+use core::snapshot::{collect_file_states, collect_file_states_with_jobs, compare_file_maps, compare_with_working_tree, create_file_map, find_snapshot_by_ref, get_snapshot, load_all_snapshots, normalize_path, relative_path, snapshot_not_found, remove_ignored, save_metadata, store_file_states};
+use core::restore::{validate_permissions,generate_restore_report, available_space, resolve_path, has_uncommitted_changes, materialize_snapshot, perform_restore, required_space, PathFilter};
 use crate::core::content::ContentStore;
-use crate::core::models::{SnapshotListInfo, StatusInfo};
-
-pub fn initialize_timemachine(base_dir: &str) -> Result<(), io::Error> {
+use crate::core::gc::mark_and_sweep;
+use crate::core::ignore_rules::IgnoreRules;
+use crate::core::retention::snapshots_to_keep;
+use crate::core::lock::RepoLock;
+use crate::core::scan_cache::ScanCache;
+use crate::core::utils::default_jobs;
+use crate::core::verify::check_repository;
+
+pub fn initialize_timemachine(base_dir: &str) -> Result<()> {
     let root_path = Path::new(base_dir);
 
     let timemachine = root_path.join(".timemachine");
@@ -29,6 +40,7 @@ pub fn initialize_timemachine(base_dir: &str) -> Result<(), io::Error> {
     if !metadata_file.exists() {
         let new_metadata = SnapshotMetadata {
             snapshots: Vec::new(),
+            next_id: 1,
         };
 
         let content = serde_json::to_string(&new_metadata)?;
@@ -39,10 +51,13 @@ pub fn initialize_timemachine(base_dir: &str) -> Result<(), io::Error> {
     Ok(())
 }
 
-pub fn take_snapshot(dir: &str) -> io::Result<()> {
+/// Records the current state of `dir` and returns the ID of the new snapshot. Files are hashed
+/// and compressed on up to `options.jobs` threads (all available cores by default). If nothing
+/// changed since the latest snapshot, no snapshot is recorded and `None` is returned, unless
+/// `options.allow_empty` is set.
+pub fn take_snapshot(dir: &str, options: &SnapshotOptions) -> Result<Option<usize>> {
     let base_path = Path::new(dir);
     let metadata_folder = base_path.join(".timemachine");
-    let metadata_file = metadata_folder.join("metadata.json");
 
     // ensure that timestamp directory exists
     if !metadata_folder.exists() {
@@ -51,72 +66,309 @@ pub fn take_snapshot(dir: &str) -> io::Result<()> {
             dir
         );
 
-        initialize_timemachine(&dir)?;
+        initialize_timemachine(dir)?;
     }
 
+    let _lock = RepoLock::acquire(dir, options.wait)?;
+    create_snapshot(
+        dir,
+        options.message.as_deref(),
+        options.jobs.unwrap_or_else(default_jobs),
+        options.allow_empty,
+    )
+}
+
+/// Records a new snapshot. The caller must hold the repository lock.
+fn create_snapshot(dir: &str, message: Option<&str>, jobs: usize, allow_empty: bool) -> Result<Option<usize>> {
     // Load snapshots from metadata.json
-    let mut metadata: SnapshotMetadata = {
-        let content = fs::read_to_string(&metadata_file)?;
-        serde_json::from_str(&content)?
-    };
+    let mut metadata = load_all_snapshots(dir)?;
 
-    let file_states = collect_file_states(&dir)?;
+    let mut file_states = collect_file_states_with_jobs(dir, jobs)?;
+    store_file_states(dir, &mut file_states, jobs)?;
 
+    // Count files added, modified, deleted or moved since the latest snapshot
+    let changes = match metadata.snapshots.last() {
+        Some(latest) => {
+            compare_file_maps(&create_file_map(&latest.file_states), &create_file_map(&file_states)).change_count()
+        }
+        None => file_states.len(),
+    };
+    if changes == 0 && !metadata.snapshots.is_empty() && !allow_empty {
+        return Ok(None);
+    }
 
     let snapshot = Snapshot {
-        id: metadata.snapshots.len() + 1,
+        id: metadata.next_id,
         timestamp: Local::now().to_rfc3339(),
-        changes: file_states.len(),
+        changes,
         file_states,
+        message: message.map(str::to_string),
+        tags: Vec::new(),
     };
 
     // update metadata
+    let snapshot_id = snapshot.id;
     metadata.snapshots.push(snapshot);
-    let updated_metadata = serde_json::to_string_pretty(&metadata)?;
-    fs::write(metadata_file, updated_metadata)?;
+    metadata.next_id += 1;
+    save_metadata(dir, &metadata)?;
 
-    Ok(())
+    Ok(Some(snapshot_id))
 }
 
+/// Compares two snapshots. Files whose content is identical on both sides are always reported
+/// as renames; with `rename_threshold`, text files that are at least that percentage similar
+/// are reported as renames with edits as well.
 pub fn differentiate_snapshots(
     path: &str,
     snapshot_id1: usize,
     snapshot_id2: usize,
-) -> io::Result<SnapshotComparison> {
+    rename_threshold: Option<u8>,
+) -> Result<SnapshotComparison> {
     let metadata = load_all_snapshots(path)?;
 
-    let snapshot1 = find_snapshot(&metadata, snapshot_id1).ok_or_else(|| {
-        io::Error::new(
-            ErrorKind::NotFound,
-            format!("Snapshot ID not found: {}", snapshot_id1),
-        )
-    })?;
-
-    let snapshot2 = find_snapshot(&metadata, snapshot_id2).ok_or_else(|| {
-        io::Error::new(
-            ErrorKind::NotFound,
-            format!("Snapshot ID not found: {}", snapshot_id2),
-        )
-    })?;
-
-    let snapshot1_map = create_file_map(&snapshot1.file_states);
-    let snapshot2_map = create_file_map(&snapshot2.file_states);
-
-    let new_files = find_new_files(&snapshot2_map, &snapshot1_map);
-    let modified_files = find_modified_files(&snapshot1_map, &snapshot2_map);
-    let deleted_files = find_deleted_files(&snapshot1_map, &snapshot2_map);
-
-    Ok(SnapshotComparison {
-        new_files,
-        modified_files,
-        deleted_files,
-    })
+    let snapshot1 = get_snapshot(&metadata, snapshot_id1)?;
+    let snapshot2 = get_snapshot(&metadata, snapshot_id2)?;
+
+    let old = SnapshotFiles::new(path, &snapshot1.file_states);
+    let new = SnapshotFiles::new(path, &snapshot2.file_states);
+
+    let mut comparison = compare_file_maps(&old.files, &new.files);
+    add_similar_renames(&mut comparison, &old, &new, rename_threshold)?;
+    Ok(comparison)
+}
+
+/// Computes line-level diffs for every file that differs between two snapshots, sorted by path.
+/// Binary files are reported without a patch; `context_lines` controls the unchanged lines
+/// shown around each hunk. Renames are detected as in `differentiate_snapshots`.
+pub fn diff_snapshot_contents(
+    path: &str,
+    snapshot_id1: usize,
+    snapshot_id2: usize,
+    context_lines: usize,
+    rename_threshold: Option<u8>,
+) -> Result<Vec<FileDiff>> {
+    let metadata = load_all_snapshots(path)?;
+
+    let snapshot1 = get_snapshot(&metadata, snapshot_id1)?;
+    let snapshot2 = get_snapshot(&metadata, snapshot_id2)?;
+
+    let old = SnapshotFiles::new(path, &snapshot1.file_states);
+    let new = SnapshotFiles::new(path, &snapshot2.file_states);
+
+    let mut comparison = compare_file_maps(&old.files, &new.files);
+    add_similar_renames(&mut comparison, &old, &new, rename_threshold)?;
+    build_file_diffs(&comparison, &old, &new, context_lines)
+}
+
+/// Compares the working tree of `dir` against snapshot `snapshot_id`, which is treated as the
+/// old side. Files the snapshot recorded that are ignored now are left out. Renames are
+/// detected as in `differentiate_snapshots`.
+pub fn differentiate_working_tree(dir: &str, snapshot_id: usize, rename_threshold: Option<u8>) -> Result<SnapshotComparison> {
+    let metadata = load_all_snapshots(dir)?;
+    let snapshot = get_snapshot(&metadata, snapshot_id)?;
+    let current_states = collect_file_states(dir)?;
+
+    let old = SnapshotFiles::new(dir, &snapshot.file_states);
+    let new = WorkingFiles::new(dir, &current_states);
+
+    let mut comparison = compare_with_working_tree(dir, &snapshot.file_states, &current_states)?;
+    add_similar_renames(&mut comparison, &old, &new, rename_threshold)?;
+    Ok(comparison)
+}
+
+/// Like `diff_snapshot_contents`, but compares snapshot `snapshot_id` against the files
+/// currently on disk.
+pub fn diff_working_tree_contents(dir: &str, snapshot_id: usize, context_lines: usize, rename_threshold: Option<u8>) -> Result<Vec<FileDiff>> {
+    let metadata = load_all_snapshots(dir)?;
+    let snapshot = get_snapshot(&metadata, snapshot_id)?;
+    let current_states = collect_file_states(dir)?;
+
+    let old = SnapshotFiles::new(dir, &snapshot.file_states);
+    let new = WorkingFiles::new(dir, &current_states);
+
+    let mut comparison = compare_with_working_tree(dir, &snapshot.file_states, &current_states)?;
+    add_similar_renames(&mut comparison, &old, &new, rename_threshold)?;
+    build_file_diffs(&comparison, &old, &new, context_lines)
+}
+
+/// Files recorded by a snapshot, read from the content store.
+struct SnapshotFiles<'a> {
+    store: ContentStore,
+    files: HashMap<String, &'a FileState>,
+}
+
+impl<'a> SnapshotFiles<'a> {
+    fn new(dir: &str, file_states: &'a [FileState]) -> Self {
+        Self {
+            store: ContentStore::new(Path::new(dir)),
+            files: create_file_map(file_states),
+        }
+    }
 }
 
+impl FileSource for SnapshotFiles<'_> {
+    fn size(&self, path: &str) -> u64 {
+        self.files[path].size
+    }
 
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(self.store.open(&self.files[path].hash)?))
+    }
+}
 
-pub fn restore_snapshot(dir: &str, snapshot_id: usize, dry_run: bool, force:bool) -> io::Result<RestoreReport> {
+/// Files in the working tree, with the sizes found by the last scan.
+struct WorkingFiles<'a> {
+    dir: &'a Path,
+    files: HashMap<String, &'a FileState>,
+}
+
+impl<'a> WorkingFiles<'a> {
+    fn new(dir: &'a str, file_states: &'a [FileState]) -> Self {
+        Self {
+            dir: Path::new(dir),
+            files: create_file_map(file_states),
+        }
+    }
+}
+
+impl FileSource for WorkingFiles<'_> {
+    fn size(&self, path: &str) -> u64 {
+        self.files[path].size
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn Read + '_>> {
+        Ok(Box::new(fs::File::open(self.dir.join(path))?))
+    }
+}
+
+fn build_file_diffs(
+    comparison: &SnapshotComparison,
+    old: &impl FileSource,
+    new: &impl FileSource,
+    context_lines: usize,
+) -> Result<Vec<FileDiff>> {
+    let mut diffs = Vec::new();
+    for file in &comparison.new_files {
+        let new_text = read_text(new, file)?;
+        diffs.push(diff_file(file, ChangeStatus::Added, Some(""), new_text.as_deref(), context_lines));
+    }
+    for detail in &comparison.modified_files {
+        let old_text = read_text(old, &detail.path)?;
+        let new_text = read_text(new, &detail.path)?;
+        diffs.push(diff_file(&detail.path, ChangeStatus::Modified, old_text.as_deref(), new_text.as_deref(), context_lines));
+    }
+    for file in &comparison.deleted_files {
+        let old_text = read_text(old, file)?;
+        diffs.push(diff_file(file, ChangeStatus::Deleted, old_text.as_deref(), Some(""), context_lines));
+    }
+    for renamed in &comparison.renamed_files {
+        let old_text = read_text(old, &renamed.old_path)?;
+        let new_text = read_text(new, &renamed.new_path)?;
+        diffs.push(diff_renamed_file(renamed, old_text.as_deref(), new_text.as_deref(), context_lines));
+    }
+    diffs.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(diffs)
+}
+
+/// Pairs the remaining new and deleted files of `comparison` by content similarity, if a
+/// threshold was requested.
+fn add_similar_renames(
+    comparison: &mut SnapshotComparison,
+    old: &impl FileSource,
+    new: &impl FileSource,
+    rename_threshold: Option<u8>,
+) -> Result<()> {
+    if let Some(threshold) = rename_threshold {
+        let similar = find_similar_renames(
+            &mut comparison.new_files,
+            &mut comparison.deleted_files,
+            old,
+            new,
+            threshold,
+        )?;
+        comparison.renamed_files.extend(similar);
+        comparison.renamed_files.sort_by(|a, b| a.new_path.cmp(&b.new_path));
+    }
+    Ok(())
+}
+
+
+
+pub fn restore_snapshot(dir: &str, snapshot_id: usize, dry_run: bool, force:bool, wait: Option<Duration>) -> Result<RestoreReport> {
+    restore_filtered(dir, snapshot_id, &PathFilter::all(), dry_run, force, wait)
+}
+
+/// Restores only the files at or below `paths` (relative to `dir`), leaving everything else
+/// untouched. Only the selected paths need to be free of uncommitted changes.
+pub fn restore_paths(dir: &str, snapshot_id: usize, paths: &[String], dry_run: bool, force: bool, wait: Option<Duration>) -> Result<RestoreReport> {
+    restore_filtered(dir, snapshot_id, &PathFilter::new(paths), dry_run, force, wait)
+}
+
+/// Writes snapshot `snapshot_id` (or only the files at or below `paths`, if any are given) into
+/// `target`, which must be empty or not yet exist. The working tree is left untouched and does
+/// not need to be clean. The returned report lists every file written as added.
+pub fn restore_to(dir: &str, snapshot_id: usize, target: &str, paths: &[String], dry_run: bool, wait: Option<Duration>) -> Result<RestoreReport> {
+    let _lock = RepoLock::acquire(dir, wait)?;
+
+    let metadata = load_all_snapshots(dir)?;
+    let snapshot = get_snapshot(&metadata, snapshot_id)?;
+    let filter = PathFilter::new(paths);
+
+    let target_path = Path::new(target);
+    if target_path.exists() && fs::read_dir(target_path)?.next().is_some() {
+        return Err(Error::TargetNotEmpty { path: target_path.to_path_buf() });
+    }
+
+    // The next snapshot would pick up the restored copy, unless the target is ignored
+    let base_path = Path::new(dir).canonicalize()?;
+    if let Ok(inside) = resolve_path(target_path)?.strip_prefix(&base_path) {
+        let relative = relative_path(&base_path, &base_path.join(inside))?;
+        if relative.is_empty()
+            || relative.split('/').next() == Some(".timemachine")
+            || !IgnoreRules::load(&base_path)?.is_ignored(&relative, true)?
+        {
+            return Err(Error::TargetInsideRepository { path: target_path.to_path_buf() });
+        }
+    }
+
+    let known_paths: Vec<&str> = snapshot.file_states.iter().map(|state| state.path.as_str()).collect();
+    if let Some(path) = filter.find_unmatched(&known_paths) {
+        return Err(Error::PathNotFound { id: snapshot_id, path: path.to_string() });
+    }
+
+    let added: Vec<String> = if dry_run {
+        let mut added: Vec<String> = known_paths
+            .into_iter()
+            .filter(|path| filter.matches(path))
+            .map(str::to_string)
+            .collect();
+        added.sort();
+        added
+    } else {
+        let required = required_space(snapshot, &filter);
+        let available = available_space(target)?;
+        if available < required {
+            return Err(Error::InsufficientSpace { required, available });
+        }
+
+        fs::create_dir_all(target_path)?;
+        eprintln!("Restoring snapshot {} into {}...", snapshot_id, target);
+        materialize_snapshot(Path::new(dir), snapshot, &filter, target_path)?
+    };
+
+    Ok(RestoreReport {
+        added,
+        modified: Vec::new(),
+        deleted: Vec::new(),
+        unchanged: Vec::new(),
+        renamed: Vec::new(),
+    })
+}
+
+fn restore_filtered(dir: &str, snapshot_id: usize, filter: &PathFilter, dry_run: bool, force: bool, wait: Option<Duration>) -> Result<RestoreReport> {
     validate_permissions(dir)?;
+    let _lock = RepoLock::acquire(dir, wait)?;
 
     let base_path = Path::new(dir);
     
@@ -124,54 +376,53 @@ pub fn restore_snapshot(dir: &str, snapshot_id: usize, dry_run: bool, force:bool
     let all_snapshots = load_all_snapshots(dir)?;
 
 
-    let snapshot = find_snapshot(&all_snapshots, snapshot_id)
-        .ok_or_else(|| io::Error::new(
-            ErrorKind::NotFound,
-            format!("Snapshot {} does not exist. Available snapshots: {}",
-                    snapshot_id,
-                    all_snapshots.snapshots.iter()
-                        .map(|s| s.id.to_string())
-                        .collect::<Vec<_>>()
-                        .join(", ")
-            )
-        ))?;
+    let snapshot = get_snapshot(&all_snapshots, snapshot_id)?;
 
     // Step 2: Ensure sufficient disk space
-    if !has_available_space(dir, snapshot)? {
-        return Err(io::Error::new(
-            ErrorKind::Other,
-            "Insufficient disk space for restoration.",
-        ));
+    let required = required_space(snapshot, filter);
+    let available = available_space(dir)?;
+    if available < required {
+        return Err(Error::InsufficientSpace { required, available });
     }
 
     // Step 3: Check for uncommitted changes
-    if has_uncommitted_changes(dir)? {
-        // if --force flag is not applied
-        if !force {
-            return Err(io::Error::new(
-                ErrorKind::Other,
-                "Uncommitted changes detected. Take another snapshot before proceeding to restore, or use --force to override (this will automatically create a backup of your current state).",
-            ));
-
-        }
-       // create a backup when using force
-        eprintln!("Creating backup snapshot of current state before force restore...");
-        take_snapshot(dir)?;
-        eprintln!("Backup snapshot created successfully.");
+    let uncommitted = has_uncommitted_changes(dir, filter)?;
+    // if --force flag is not applied
+    if uncommitted && !force {
+        return Err(Error::UncommittedChanges);
     }
 
-
-
     // Step 4: Generate restore report
     let current_states = collect_file_states(dir)?;
-    let current_map = create_file_map(&current_states);
-    let snapshot_map = create_file_map(&snapshot.file_states);
+    let mut current_map = create_file_map(&current_states);
+    let mut snapshot_map = create_file_map(&snapshot.file_states);
+    remove_ignored(dir, &mut snapshot_map)?;
+    current_map.retain(|path, _| filter.matches(path));
+    snapshot_map.retain(|path, _| filter.matches(path));
+
+    let known_paths: Vec<&str> = current_states
+        .iter()
+        .chain(&snapshot.file_states)
+        .map(|state| state.path.as_str())
+        .collect();
+    if let Some(path) = filter.find_unmatched(&known_paths) {
+        return Err(Error::PathNotFound { id: snapshot_id, path: path.to_string() });
+    }
+
     let report = generate_restore_report(&current_map, &snapshot_map);
 
     if dry_run {
         return Ok(report); // Dry run; do not apply changes
     }
 
+    // create a backup when using force, once nothing can stop the restore short of an I/O error
+    if uncommitted {
+        eprintln!("Creating backup snapshot of current state before force restore...");
+        let backup_message = format!("Backup before force restore to snapshot {}", snapshot_id);
+        create_snapshot(dir, Some(&backup_message), default_jobs(), true)?;
+        eprintln!("Backup snapshot created successfully.");
+    }
+
     // Step 5: Execute restore operations
     eprintln!("Restoring to snapshot {}...", snapshot_id);
 
@@ -182,7 +433,22 @@ pub fn restore_snapshot(dir: &str, snapshot_id: usize, dry_run: bool, force:bool
     Ok(report)
 }
 
-pub fn list_snapshots(dir: &str, detailed: bool) -> io::Result<Vec<SnapshotListInfo>> {
+/// Opens `path` (relative to `dir`) as it was in snapshot `snapshot_id` and returns a reader
+/// over its decompressed content.
+pub fn open_snapshot_file(dir: &str, snapshot_id: usize, path: &str) -> Result<impl Read> {
+    let metadata = load_all_snapshots(dir)?;
+    let snapshot = get_snapshot(&metadata, snapshot_id)?;
+    let path = normalize_path(path);
+
+    let file_state = snapshot.file_states
+        .iter()
+        .find(|state| state.path == path)
+        .ok_or_else(|| Error::PathNotFound { id: snapshot_id, path: path.clone() })?;
+
+    ContentStore::new(Path::new(dir)).open(&file_state.hash)
+}
+
+pub fn list_snapshots(dir: &str, detailed: bool) -> Result<Vec<SnapshotListInfo>> {
     let metadata = load_all_snapshots(dir)?;
     
     let mut snapshot_info = Vec::new();
@@ -198,13 +464,71 @@ pub fn list_snapshots(dir: &str, detailed: bool) -> io::Result<Vec<SnapshotListI
             timestamp: snapshot.timestamp,
             changes: snapshot.changes,
             total_size,
+            message: snapshot.message,
+            tags: snapshot.tags,
         });
     }
     
     Ok(snapshot_info)
 }
 
-pub fn get_status(dir: &str) -> io::Result<StatusInfo> {
+/// Resolves a snapshot reference, either a numeric id or a tag name, to a snapshot id.
+pub fn resolve_snapshot_ref(dir: &str, reference: &str) -> Result<usize> {
+    let metadata = load_all_snapshots(dir)?;
+
+    find_snapshot_by_ref(&metadata, reference)
+        .map(|snapshot| snapshot.id)
+        .ok_or_else(|| match reference.parse::<usize>() {
+            Ok(id) => snapshot_not_found(&metadata, id),
+            Err(_) => Error::TagNotFound { tag: reference.to_string() },
+        })
+}
+
+pub fn tag_snapshot(dir: &str, snapshot_id: usize, tag: &str, wait: Option<Duration>) -> Result<()> {
+    if tag.is_empty() || tag.parse::<usize>().is_ok() {
+        return Err(Error::InvalidTag { tag: tag.to_string() });
+    }
+
+    let _lock = RepoLock::acquire(dir, wait)?;
+    let mut metadata = load_all_snapshots(dir)?;
+
+    if let Some(existing) = find_snapshot_by_ref(&metadata, tag) {
+        return Err(Error::TagExists { tag: tag.to_string(), id: existing.id });
+    }
+
+    let not_found = snapshot_not_found(&metadata, snapshot_id);
+    let snapshot = metadata.snapshots
+        .iter_mut()
+        .find(|s| s.id == snapshot_id)
+        .ok_or(not_found)?;
+    snapshot.tags.push(tag.to_string());
+
+    save_metadata(dir, &metadata)?;
+
+    Ok(())
+}
+
+pub fn untag_snapshot(dir: &str, tag: &str, wait: Option<Duration>) -> Result<()> {
+    let _lock = RepoLock::acquire(dir, wait)?;
+    let mut metadata = load_all_snapshots(dir)?;
+
+    let snapshot = metadata.snapshots
+        .iter_mut()
+        .find(|s| s.tags.iter().any(|t| t == tag))
+        .ok_or_else(|| Error::TagNotFound { tag: tag.to_string() })?;
+    snapshot.tags.retain(|t| t != tag);
+
+    save_metadata(dir, &metadata)?;
+
+    Ok(())
+}
+
+/// Discards the scan cache of `dir`, so the next scan reads and hashes every file again.
+pub fn clear_scan_cache(dir: &str) -> Result<()> {
+    Ok(ScanCache::clear(Path::new(dir))?)
+}
+
+pub fn get_status(dir: &str) -> Result<StatusInfo> {
     let metadata = load_all_snapshots(dir)?;
     let latest_snapshot = metadata.snapshots.last();
     let latest_snapshot_id = latest_snapshot.map(|s| s.id);
@@ -215,58 +539,136 @@ pub fn get_status(dir: &str) -> io::Result<StatusInfo> {
         modified_files: Vec::new(),
         new_files: Vec::new(),
         deleted_files: Vec::new(),
+        renamed_files: Vec::new(),
         available_space: 0,
         latest_snapshot_id,
     };
     
     // Get available space using sysinfo
-    let base_dir = Path::new(dir);
-    let abs_path = base_dir.canonicalize()?;
-    let disks = Disks::new_with_refreshed_list_specifics(DiskRefreshKind::everything());
-    status.available_space = disks
-        .iter()
-        .find(|disk| abs_path.starts_with(disk.mount_point()))
-        .map(|disk| disk.available_space())
-        .unwrap_or(0);
+    status.available_space = available_space(dir)?;
     
     if let Some(snapshot) = latest_snapshot {
-        let current_map = create_file_map(&current_states);
-        let snapshot_map = create_file_map(&snapshot.file_states);
-        
-        status.modified_files = find_modified_files(&snapshot_map, &current_map)
+        let comparison = compare_with_working_tree(dir, &snapshot.file_states, &current_states)?;
+
+        status.has_uncommitted_changes = comparison.change_count() > 0;
+        status.modified_files = comparison.modified_files
             .into_iter()
             .map(|m| m.path)
             .collect();
-        status.new_files = find_new_files(&current_map, &snapshot_map);
-        status.deleted_files = find_deleted_files(&snapshot_map, &current_map);
+        status.new_files = comparison.new_files;
+        status.deleted_files = comparison.deleted_files;
+        status.renamed_files = comparison.renamed_files;
         
-        status.has_uncommitted_changes = !status.modified_files.is_empty() 
-            || !status.new_files.is_empty() 
-            || !status.deleted_files.is_empty();
     }
     
     Ok(status)
 }
 
+/// Checks the metadata and content store of `dir` for damage. With `sample_percent`, only
+/// about that share of the stored blobs is decompressed and re-hashed; all of them are still
+/// checked for existence. Blobs are checked on up to `jobs` threads (all available cores by
+/// default).
+pub fn verify_repository(dir: &str, sample_percent: Option<u8>, jobs: Option<usize>, wait: Option<Duration>) -> Result<VerifyReport> {
+    ensure_repository(dir)?;
+    let _lock = RepoLock::acquire(dir, wait)?;
+    check_repository(dir, sample_percent, jobs.unwrap_or_else(default_jobs))
+}
+
+/// Decompresses and re-hashes every blob referenced by a snapshot of `dir` on up to `jobs`
+/// threads, and returns the result for each blob in hash order along with the throughput.
+/// Blobs are streamed, so memory use does not depend on their size.
+pub fn verify_all(dir: &str, jobs: Option<usize>, wait: Option<Duration>) -> Result<BlobVerification> {
+    ensure_repository(dir)?;
+    let _lock = RepoLock::acquire(dir, wait)?;
+
+    let metadata = load_all_snapshots(dir)?;
+    let hashes: BTreeSet<&str> = metadata.snapshots
+        .iter()
+        .flat_map(|snapshot| snapshot.file_states.iter().map(|state| state.hash.as_str()))
+        .collect();
+    let hashes: Vec<&str> = hashes.into_iter().collect();
+
+    let store = ContentStore::new(Path::new(dir));
+    Ok(store.verify_all(&hashes, jobs.unwrap_or_else(default_jobs)))
+}
+
+/// Removes stored blobs that no snapshot of `dir` references and that are older than `grace`.
+/// With `dry_run`, only reports what would be removed.
+pub fn collect_garbage(dir: &str, grace: Duration, dry_run: bool, wait: Option<Duration>) -> Result<GcReport> {
+    ensure_repository(dir)?;
+    let _lock = RepoLock::acquire(dir, wait)?;
+    mark_and_sweep(dir, grace, dry_run)
+}
 
-pub fn delete_snapshot(dir: &str, snapshot_id: usize, cleanup: bool) -> io::Result<()> {
+/// Deletes the snapshots of `dir` that `policy` does not keep, then garbage collects their
+/// content with the given `grace` period. With `dry_run`, only reports which snapshots would
+/// be deleted.
+pub fn prune_snapshots(
+    dir: &str,
+    policy: &RetentionPolicy,
+    grace: Duration,
+    dry_run: bool,
+    wait: Option<Duration>,
+) -> Result<PruneReport> {
+    ensure_repository(dir)?;
+    let _lock = RepoLock::acquire(dir, wait)?;
+
+    let mut metadata = load_all_snapshots(dir)?;
+    let keep = snapshots_to_keep(&metadata.snapshots, policy);
+    let (kept, removed): (Vec<Snapshot>, Vec<Snapshot>) = metadata.snapshots
+        .drain(..)
+        .partition(|snapshot| keep.contains(&snapshot.id));
+
+    let mut report = PruneReport {
+        dry_run,
+        kept: kept.iter().map(|snapshot| snapshot.id).collect(),
+        removed: removed
+            .into_iter()
+            .map(|snapshot| SnapshotListInfo {
+                id: snapshot.id,
+                timestamp: snapshot.timestamp,
+                changes: snapshot.changes,
+                total_size: snapshot.file_states.iter().map(|state| state.size).sum(),
+                message: snapshot.message,
+                tags: snapshot.tags,
+            })
+            .collect(),
+        gc: None,
+    };
+
+    if !dry_run {
+        metadata.snapshots = kept;
+        save_metadata(dir, &metadata)?;
+        report.gc = Some(mark_and_sweep(dir, grace, false)?);
+    }
+
+    Ok(report)
+}
+
+/// Fails unless `dir` has been initialized, so that read-only commands do not create the
+/// `.timemachine` folder as a side effect of taking the lock.
+fn ensure_repository(dir: &str) -> Result<()> {
+    if !Path::new(dir).join(".timemachine").is_dir() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("'{}' is not a timemachine repository", dir),
+        )));
+    }
+    Ok(())
+}
+
+pub fn delete_snapshot(dir: &str, snapshot_id: usize, cleanup: bool, wait: Option<Duration>) -> Result<()> {
     let base_path = Path::new(dir);
-    let metadata_path = base_path.join(".timemachine").join("metadata.json");
+    let _lock = RepoLock::acquire(dir, wait)?;
     
     // Load metadata
-    let mut metadata: SnapshotMetadata = {
-        let content = fs::read_to_string(&metadata_path)?;
-        serde_json::from_str(&content)?
-    };
+    let mut metadata = load_all_snapshots(dir)?;
     
     // Find the snapshot to delete
     let snapshot_index = metadata.snapshots
         .iter()
         .position(|s| s.id == snapshot_id)
-        .ok_or_else(|| io::Error::new(
-            ErrorKind::NotFound,
-            format!("Snapshot {} not found", snapshot_id)
-        ))?;
+        .ok_or_else(|| snapshot_not_found(&metadata, snapshot_id))?;
 
     let store = ContentStore::new(base_path);
     
@@ -304,8 +706,7 @@ pub fn delete_snapshot(dir: &str, snapshot_id: usize, cleanup: bool) -> io::Resu
     metadata.snapshots.remove(snapshot_index);
     
     // Save updated metadata
-    let updated_content = serde_json::to_string_pretty(&metadata)?;
-    fs::write(&metadata_path, updated_content)?;
+    save_metadata(dir, &metadata)?;
 
     eprintln!("Snapshots remaining: {}", metadata.snapshots.len());
     
@@ -331,6 +732,7 @@ pub fn delete_snapshot(dir: &str, snapshot_id: usize, cleanup: bool) -> io::Resu
 mod tests {
     use super::*;
     use std::fs;
+    use std::io;
     use std::fs::{File, OpenOptions};
     use std::io::Write;
     use std::path::Path;
@@ -353,11 +755,114 @@ mod tests {
 
 
         let metadata_content = fs::read_to_string(metadata_path).unwrap();
-        assert_eq!(metadata_content, r#"{"snapshots":[]}"#);
+        assert_eq!(metadata_content, r#"{"snapshots":[],"next_id":1}"#);
 
         // Temporary directory is automatically cleaned up
     }
 
+    #[test]
+    fn test_take_snapshot_parallel_is_deterministic() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+        for i in 0..50 {
+            let nested = Path::new(dir).join(format!("dir{}", i % 5));
+            fs::create_dir_all(&nested)?;
+            fs::write(nested.join(format!("file{}.txt", i)), format!("content {}", i % 7))?;
+        }
+
+        take_snapshot(dir, &SnapshotOptions { jobs: Some(1), ..Default::default() })?;
+        clear_scan_cache(dir)?;
+        take_snapshot(dir, &SnapshotOptions { jobs: Some(8), allow_empty: true, ..Default::default() })?;
+
+        let metadata = load_all_snapshots(dir)?;
+        assert_eq!(metadata.snapshots[0].file_states.len(), 50);
+        assert_eq!(metadata.snapshots[0].file_states, metadata.snapshots[1].file_states);
+        for state in &metadata.snapshots[1].file_states {
+            assert!(ContentStore::new(Path::new(dir)).contains(&state.hash));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_snapshot_skips_unchanged_tree() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let base_path = Path::new(dir);
+
+        initialize_timemachine(dir)?;
+        fs::write(base_path.join("a.txt"), "a")?;
+        fs::write(base_path.join("b.txt"), "b")?;
+        fs::write(base_path.join("c.txt"), "c")?;
+        assert_eq!(take_snapshot(dir, &SnapshotOptions::default())?, Some(1));
+
+        assert_eq!(take_snapshot(dir, &SnapshotOptions::default())?, None);
+        assert_eq!(take_snapshot(dir, &SnapshotOptions { allow_empty: true, ..Default::default() })?, Some(2));
+
+        fs::write(base_path.join("a.txt"), "changed")?;
+        fs::remove_file(base_path.join("b.txt"))?;
+        fs::rename(base_path.join("c.txt"), base_path.join("d.txt"))?;
+        assert_eq!(take_snapshot(dir, &SnapshotOptions::default())?, Some(3));
+
+        let changes: Vec<usize> = list_snapshots(dir, false)?.iter().map(|s| s.changes).collect();
+        assert_eq!(changes, vec![3, 0, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_touched_file_is_not_a_change() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let file = Path::new(dir).join("a.txt");
+
+        initialize_timemachine(dir)?;
+        fs::write(&file, "a")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // Only the modification time changes, as with `touch -d 2020-01-01 a.txt`
+        let past = std::time::UNIX_EPOCH + Duration::from_secs(1_577_836_800);
+        File::options().write(true).open(&file)?.set_modified(past)?;
+
+        // status, snapshot and restore all agree that nothing changed
+        assert!(!get_status(dir)?.has_uncommitted_changes);
+        assert_eq!(take_snapshot(dir, &SnapshotOptions::default())?, None);
+        let report = restore_snapshot(dir, 1, false, false, None)?;
+        assert_eq!(report.unchanged, vec!["a.txt"]);
+        assert_eq!(list_snapshots(dir, false)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_snapshot_replaces_truncated_blob() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let base_path = Path::new(dir);
+
+        initialize_timemachine(dir)?;
+        fs::write(base_path.join("a.txt"), "a".repeat(10_000))?;
+        fs::write(base_path.join("b.txt"), "b")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // Cut the blob short without emptying it, as a crash during an earlier write could
+        let hash = load_all_snapshots(dir)?.snapshots[0].file_states[0].hash.clone();
+        let blob_path = base_path.join(".timemachine").join("contents").join(&hash);
+        let blob = fs::read(&blob_path)?;
+        fs::write(&blob_path, &blob[..blob.len() / 2])?;
+        let store = ContentStore::new(base_path);
+        assert!(store.contains(&hash));
+        assert!(!matches!(store.verify_content(&hash), Ok(true)));
+
+        fs::write(base_path.join("b.txt"), "changed")?;
+        assert_eq!(take_snapshot(dir, &SnapshotOptions::default())?, Some(2));
+        assert!(store.verify_content(&hash)?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_take_snapshot() {
         let test_dir = tempdir().unwrap(); // Use a unique temp directory
@@ -376,7 +881,7 @@ mod tests {
         writeln!(f2, "Time Machine").unwrap();
 
         // Take a snapshot
-        take_snapshot(test_path).unwrap();
+        take_snapshot(test_path, &SnapshotOptions::default()).unwrap();
 
         // Verify metadata.json is updated
         let metadata_path = Path::new(test_path)
@@ -410,7 +915,7 @@ mod tests {
         writeln!(f2, "Time Machine").unwrap();
 
         // Take the first snapshot
-        take_snapshot(test_path).unwrap();
+        take_snapshot(test_path, &SnapshotOptions::default()).unwrap();
 
         // Modify one of the files
         let mut f1 = File::create(&file1).unwrap();
@@ -422,10 +927,10 @@ mod tests {
         writeln!(f3, "New file in second snapshot").unwrap();
 
         // Take the second snapshot
-        take_snapshot(test_path).unwrap();
+        take_snapshot(test_path, &SnapshotOptions::default()).unwrap();
 
         // Compare the two snapshots (ID 1 and ID 2)
-        let comparison = differentiate_snapshots(test_path, 1, 2).unwrap();
+        let comparison = differentiate_snapshots(test_path, 1, 2, None).unwrap();
 
         // Assert that the comparison is correct
         assert_eq!(comparison.new_files, vec!["file3.txt"]);
@@ -465,7 +970,7 @@ mod tests {
         // Initialize content store and store the file
         let store = ContentStore::new(base_path);
         store.init()?;
-        let hash = store.store_file(&file1_path)?;
+        let (hash, _) = store.store_file(&file1_path)?;
 
         // Create metadata with the actual hash
         let metadata = SnapshotMetadata {
@@ -479,7 +984,10 @@ mod tests {
                     size: 12,
                     last_modified: "timestamp".to_string(),
                 }],
+                message: None,
+                tags: vec![],
             }],
+            next_id: 2,
         };
 
         let metadata_dir = base_path.join(".timemachine");
@@ -498,6 +1006,7 @@ mod tests {
             modified: vec![],
             deleted: vec![],
             unchanged: vec![],
+            renamed: vec![],
         };
 
         perform_restore(base_path, 1, &report)?;
@@ -508,6 +1017,518 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_restore_nested_files() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+
+        // Create a nested tree and take a snapshot
+        let nested_dir = Path::new(dir).join("src").join("core");
+        fs::create_dir_all(&nested_dir)?;
+        let nested_file = nested_dir.join("lib.rs");
+        fs::write(&nested_file, "original")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // Add another nested file and modify the first one
+        let extra_dir = Path::new(dir).join("docs");
+        fs::create_dir_all(&extra_dir)?;
+        fs::write(extra_dir.join("notes.md"), "notes")?;
+        fs::write(&nested_file, "changed")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        let comparison = differentiate_snapshots(dir, 1, 2, None)?;
+        assert_eq!(comparison.new_files, vec!["docs/notes.md"]);
+        assert_eq!(comparison.modified_files[0].path, "src/core/lib.rs");
+
+        let report = restore_snapshot(dir, 1, false, false, None)?;
+        assert_eq!(report.deleted, vec!["docs/notes.md"]);
+        assert_eq!(fs::read_to_string(&nested_file)?, "original");
+        assert!(!extra_dir.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_path_switching_between_file_and_directory() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+
+        // `thing` is a file in snapshot 1 and a directory in snapshot 2
+        let thing = Path::new(dir).join("thing");
+        fs::write(&thing, "file")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        fs::remove_file(&thing)?;
+        fs::create_dir_all(&thing)?;
+        fs::write(thing.join("inner"), "nested")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // A directory in the way of a file
+        let report = restore_snapshot(dir, 1, false, false, None)?;
+        assert_eq!(report.added, vec!["thing"]);
+        assert_eq!(report.deleted, vec!["thing/inner"]);
+        assert_eq!(fs::read_to_string(&thing)?, "file");
+
+        // A file in the way of a directory; the tree now differs from the latest snapshot
+        let report = restore_snapshot(dir, 2, false, true, None)?;
+        assert_eq!(report.added, vec!["thing/inner"]);
+        assert_eq!(report.deleted, vec!["thing"]);
+        assert_eq!(fs::read_to_string(thing.join("inner"))?, "nested");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignored_files_are_not_touched() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+
+        // Snapshot a file that is later excluded by the ignore file
+        let cache_file = Path::new(dir).join("cache.tmp");
+        fs::write(&cache_file, "cached v1")?;
+        fs::write(Path::new(dir).join("main.rs"), "fn main() {}")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        fs::write(Path::new(dir).join(".timemachineignore"), "*.tmp\nbuild/\n")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // Ignored files do not show up in status
+        fs::write(&cache_file, "cached v2")?;
+        let build_dir = Path::new(dir).join("build");
+        fs::create_dir_all(&build_dir)?;
+        fs::write(build_dir.join("out.o"), "object")?;
+        let status = get_status(dir)?;
+        assert!(!status.has_uncommitted_changes);
+
+        // Restoring either snapshot leaves ignored files alone
+        restore_snapshot(dir, 1, false, true, None)?;
+        assert_eq!(fs::read_to_string(&cache_file)?, "cached v2");
+        assert!(build_dir.join("out.o").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_paths() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+
+        let src_dir = Path::new(dir).join("src");
+        let docs_dir = Path::new(dir).join("docs");
+        fs::create_dir_all(&src_dir)?;
+        fs::create_dir_all(&docs_dir)?;
+        fs::write(src_dir.join("config.toml"), "original config")?;
+        fs::write(src_dir.join("main.rs"), "original main")?;
+        fs::write(docs_dir.join("guide.md"), "original guide")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // Change the selected paths as well as the rest of the tree, without a new snapshot
+        fs::write(src_dir.join("config.toml"), "broken config")?;
+        fs::write(docs_dir.join("extra.md"), "extra")?;
+        fs::write(src_dir.join("main.rs"), "work in progress")?;
+        fs::write(Path::new(dir).join("new.txt"), "new")?;
+
+        // Uncommitted changes in the selected paths still require --force
+        let paths = vec!["src/config.toml".to_string(), "docs/".to_string()];
+        assert!(matches!(
+            restore_paths(dir, 1, &paths, false, false, None),
+            Err(Error::UncommittedChanges)
+        ));
+
+        // The backup snapshot of --force is only taken for a restore that goes ahead
+        restore_paths(dir, 1, &paths, true, true, None)?;
+        let missing = vec!["src/config.toml".to_string(), "missing".to_string()];
+        assert!(matches!(
+            restore_paths(dir, 1, &missing, false, true, None),
+            Err(Error::PathNotFound { .. })
+        ));
+        assert_eq!(list_snapshots(dir, false)?.len(), 1);
+        assert_eq!(fs::read_to_string(src_dir.join("config.toml"))?, "broken config");
+
+        // Changes outside the selected paths do not block the restore
+        fs::write(src_dir.join("config.toml"), "original config")?;
+        fs::remove_file(docs_dir.join("extra.md"))?;
+        fs::write(docs_dir.join("guide.md"), "edited guide")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+        fs::write(src_dir.join("config.toml"), "edited config")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+        fs::write(src_dir.join("main.rs"), "more work")?;
+
+        let report = restore_paths(dir, 1, &paths, false, false, None)?;
+        assert_eq!(report.modified, vec!["docs/guide.md", "src/config.toml"]);
+        assert!(report.deleted.is_empty());
+        assert_eq!(fs::read_to_string(src_dir.join("config.toml"))?, "original config");
+        assert_eq!(fs::read_to_string(docs_dir.join("guide.md"))?, "original guide");
+        assert_eq!(fs::read_to_string(src_dir.join("main.rs"))?, "more work");
+        assert!(Path::new(dir).join("new.txt").exists());
+
+        // A selected file whose content is unchanged but whose timestamp moved is not a change
+        take_snapshot(dir, &SnapshotOptions::default())?;
+        let past = std::time::UNIX_EPOCH + Duration::from_secs(1_577_836_800);
+        File::options().write(true).open(src_dir.join("config.toml"))?.set_modified(past)?;
+        let report = restore_paths(dir, 1, &["src/config.toml".to_string()], false, false, None)?;
+        assert_eq!(report.unchanged, vec!["src/config.toml"]);
+
+        // Paths that match nothing are reported
+        assert!(matches!(
+            restore_paths(dir, 1, &["missing".to_string()], true, false, None),
+            Err(Error::PathNotFound { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_to() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let target_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let target = target_dir.path().join("inspect");
+        let target = target.to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+
+        let nested_dir = Path::new(dir).join("src");
+        fs::create_dir_all(&nested_dir)?;
+        fs::write(nested_dir.join("lib.rs"), "v1")?;
+        fs::write(Path::new(dir).join("notes.txt"), "notes")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // Uncommitted changes in the working tree are fine and stay untouched
+        fs::write(nested_dir.join("lib.rs"), "work in progress")?;
+
+        let report = restore_to(dir, 1, target, &[], false, None)?;
+        assert_eq!(report.added, vec!["notes.txt", "src/lib.rs"]);
+        assert_eq!(fs::read_to_string(Path::new(target).join("src/lib.rs"))?, "v1");
+        assert_eq!(fs::read_to_string(nested_dir.join("lib.rs"))?, "work in progress");
+
+        // The target must be empty
+        assert!(matches!(
+            restore_to(dir, 1, target, &[], false, None),
+            Err(Error::TargetNotEmpty { .. })
+        ));
+
+        // A target inside the tracked directory is only accepted when it is ignored
+        let inside = Path::new(dir).join("restored").join("copy");
+        let inside = inside.to_str().unwrap();
+        assert!(matches!(
+            restore_to(dir, 1, inside, &[], false, None),
+            Err(Error::TargetInsideRepository { .. })
+        ));
+        assert!(!Path::new(dir).join("restored").exists());
+        fs::write(Path::new(dir).join(".timemachineignore"), "restored/\n")?;
+        restore_to(dir, 1, inside, &[], false, None)?;
+        assert_eq!(fs::read_to_string(Path::new(inside).join("notes.txt"))?, "notes");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_snapshot_contents() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+
+        fs::write(Path::new(dir).join("notes.txt"), "alpha\nbeta\ngamma\n")?;
+        fs::write(Path::new(dir).join("logo.bin"), b"\0\x01\x02")?;
+        fs::write(Path::new(dir).join("old.txt"), "going away\n")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        fs::write(Path::new(dir).join("notes.txt"), "alpha\nBETA\ngamma\n")?;
+        fs::write(Path::new(dir).join("logo.bin"), b"\0\x01\x03")?;
+        fs::remove_file(Path::new(dir).join("old.txt"))?;
+        fs::write(Path::new(dir).join("new.txt"), "fresh\n")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        let diffs = diff_snapshot_contents(dir, 1, 2, 3, None)?;
+        let paths: Vec<_> = diffs.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec!["logo.bin", "new.txt", "notes.txt", "old.txt"]);
+
+        assert!(diffs[0].binary);
+        assert_eq!(diffs[1].status, ChangeStatus::Added);
+        assert_eq!((diffs[2].insertions, diffs[2].deletions), (1, 1));
+        assert!(diffs[2].patch.as_ref().unwrap().contains("-beta\n+BETA\n"));
+        assert_eq!(diffs[3].status, ChangeStatus::Deleted);
+        assert_eq!((diffs[3].insertions, diffs[3].deletions), (0, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_working_tree() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+
+        fs::write(Path::new(dir).join("keep.txt"), "v1\n")?;
+        fs::write(Path::new(dir).join("remove.txt"), "bye\n")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+        fs::write(Path::new(dir).join("keep.txt"), "v2\n")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        fs::write(Path::new(dir).join("keep.txt"), "v3\n")?;
+        fs::remove_file(Path::new(dir).join("remove.txt"))?;
+        fs::write(Path::new(dir).join("added.txt"), "hi\n")?;
+
+        // Compare against the older snapshot, not just the latest one
+        let comparison = differentiate_working_tree(dir, 1, None)?;
+        assert_eq!(comparison.new_files, vec!["added.txt"]);
+        assert_eq!(comparison.modified_files.len(), 1);
+        assert_eq!(comparison.modified_files[0].path, "keep.txt");
+        assert_eq!(comparison.deleted_files, vec!["remove.txt"]);
+
+        let diffs = diff_working_tree_contents(dir, 1, 3, None)?;
+        assert_eq!(diffs.len(), 3);
+        assert!(diffs[1].patch.as_ref().unwrap().contains("-v1\n+v3\n"));
+
+        assert!(matches!(
+            differentiate_working_tree(dir, 9, None),
+            Err(Error::SnapshotNotFound { id: 9, .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_detection() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let base = Path::new(dir);
+
+        initialize_timemachine(dir)?;
+
+        fs::write(base.join("moved.txt"), "same content\n")?;
+        fs::write(base.join("edited.txt"), "one\ntwo\nthree\nfour\n")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        fs::create_dir_all(base.join("docs"))?;
+        fs::rename(base.join("moved.txt"), base.join("docs/moved.txt"))?;
+        fs::remove_file(base.join("edited.txt"))?;
+        fs::write(base.join("renamed.txt"), "one\ntwo\nthree\nFOUR\n")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // Identical content is always paired
+        let comparison = differentiate_snapshots(dir, 1, 2, None)?;
+        assert_eq!(comparison.renamed_files, vec![RenamedFile {
+            old_path: "moved.txt".to_string(),
+            new_path: "docs/moved.txt".to_string(),
+            similarity: 100,
+        }]);
+        assert_eq!(comparison.new_files, vec!["renamed.txt"]);
+        assert_eq!(comparison.deleted_files, vec!["edited.txt"]);
+
+        // Edited files are only paired when a threshold is given
+        let comparison = differentiate_snapshots(dir, 1, 2, Some(50))?;
+        assert_eq!(comparison.renamed_files.len(), 2);
+        assert_eq!(comparison.renamed_files[1].old_path, "edited.txt");
+        assert_eq!(comparison.renamed_files[1].similarity, 75);
+        assert!(comparison.new_files.is_empty());
+        assert!(comparison.deleted_files.is_empty());
+
+        let diffs = diff_snapshot_contents(dir, 1, 2, 3, Some(50))?;
+        assert_eq!(diffs[1].status, ChangeStatus::Renamed);
+        assert!(diffs[1].patch.as_ref().unwrap().contains("-four\n+FOUR\n"));
+
+        // Status and restore see the move of the working file as a rename too
+        fs::rename(base.join("docs/moved.txt"), base.join("back.txt"))?;
+        assert_eq!(get_status(dir)?.renamed_files.len(), 1);
+
+        let report = restore_snapshot(dir, 2, false, true, None)?;
+        assert_eq!(report.renamed.len(), 1);
+        assert!(report.added.is_empty() && report.deleted.is_empty());
+        assert_eq!(fs::read_to_string(base.join("docs/moved.txt"))?, "same content\n");
+        assert!(!base.join("back.txt").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_cache_and_rehash() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let file = Path::new(dir).join("file.txt");
+
+        initialize_timemachine(dir)?;
+        fs::write(&file, "original")?;
+        let modified = fs::metadata(&file)?.modified()?;
+        // Backdate the file so it is old enough to be cached
+        let past = modified - Duration::from_secs(60);
+        File::options().write(true).open(&file)?.set_modified(past)?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+        assert!(Path::new(dir).join(".timemachine/scan_cache.json").exists());
+
+        // Plant a bogus hash in the cache: a cached scan trusts it, a rehash does not
+        let cache_path = Path::new(dir).join(".timemachine/scan_cache.json");
+        let real_hash = collect_file_states(dir)?[0].hash.clone();
+        let fake_hash = "0".repeat(64);
+        fs::write(&cache_path, fs::read_to_string(&cache_path)?.replace(&real_hash, &fake_hash))?;
+        assert_eq!(collect_file_states(dir)?[0].hash, fake_hash);
+
+        clear_scan_cache(dir)?;
+        assert_eq!(collect_file_states(dir)?[0].hash, real_hash);
+
+        // Content changes that also change the size are always noticed
+        fs::write(&file, "changed content")?;
+        File::options().write(true).open(&file)?.set_modified(past)?;
+        assert!(get_status(dir)?.has_uncommitted_changes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_snapshot_file() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+
+        let nested_dir = Path::new(dir).join("config");
+        fs::create_dir_all(&nested_dir)?;
+        fs::write(nested_dir.join("app.toml"), "version = 1")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+        fs::write(nested_dir.join("app.toml"), "version = 2")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        let mut content = String::new();
+        open_snapshot_file(dir, 1, "./config/app.toml")?.read_to_string(&mut content)?;
+        assert_eq!(content, "version = 1");
+
+        assert!(matches!(
+            open_snapshot_file(dir, 1, "config/missing.toml"),
+            Err(Error::PathNotFound { id: 1, .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_repository() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let contents_dir = Path::new(dir).join(".timemachine").join("contents");
+
+        assert!(verify_repository(dir, None, None, None).is_err());
+        assert!(!Path::new(dir).join(".timemachine").exists());
+
+        initialize_timemachine(dir)?;
+        fs::write(Path::new(dir).join("a.txt"), "alpha")?;
+        fs::write(Path::new(dir).join("b.txt"), "beta")?;
+        fs::write(Path::new(dir).join("c.txt"), "gamma")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        let report = verify_repository(dir, None, None, None)?;
+        assert!(report.is_healthy());
+        assert_eq!(report.snapshots_checked, 1);
+        assert_eq!(report.blobs_referenced, 3);
+        assert_eq!(report.blobs_verified, 3);
+        assert_eq!(report.bytes_verified, 14);
+
+        let verification = verify_all(dir, Some(2), None)?;
+        assert_eq!(verification.blobs.len(), 3);
+        assert_eq!(verification.failed().count(), 0);
+
+        let metadata = load_all_snapshots(dir)?;
+        let hash_of = |path: &str| {
+            metadata.snapshots[0].file_states.iter().find(|s| s.path == path).unwrap().hash.clone()
+        };
+        fs::remove_file(contents_dir.join(hash_of("a.txt")))?;
+        fs::write(contents_dir.join(hash_of("b.txt")), "not zstd")?;
+        fs::write(contents_dir.join("unreferenced"), "x")?;
+        fs::write(contents_dir.join(".tmpAbC123"), "partial")?;
+
+        // Duplicate the snapshot under the same ID
+        let mut duplicated = load_all_snapshots(dir)?;
+        let copy = load_all_snapshots(dir)?.snapshots.remove(0);
+        duplicated.snapshots.push(copy);
+        save_metadata(dir, &duplicated)?;
+
+        let report = verify_repository(dir, None, None, None)?;
+        assert!(!report.is_healthy());
+        assert_eq!(report.metadata_error, None);
+        assert_eq!(report.duplicate_ids, vec![1]);
+        assert_eq!(report.missing_blobs.len(), 1);
+        assert_eq!(report.missing_blobs[0].paths, vec!["a.txt".to_string()]);
+        assert_eq!(report.missing_blobs[0].snapshot_ids, vec![1]);
+        assert_eq!(report.corrupt_blobs.len(), 1);
+        assert_eq!(report.corrupt_blobs[0].hash, hash_of("b.txt"));
+        assert_eq!(report.orphaned_blobs, vec!["unreferenced".to_string()]);
+
+        // Sampling still finds every missing blob
+        let report = verify_repository(dir, Some(1), None, None)?;
+        assert_eq!(report.missing_blobs.len(), 1);
+        assert!(report.blobs_verified <= 2);
+
+        // A corrupt metadata.json is reported, and the backup is checked as stored
+        let metadata_path = Path::new(dir).join(".timemachine").join("metadata.json");
+        let backup_path = Path::new(dir).join(".timemachine").join("metadata.json.bak");
+        fs::copy(&metadata_path, &backup_path)?;
+        fs::write(&metadata_path, "{")?;
+        let report = verify_repository(dir, None, None, None)?;
+        assert!(report.metadata_error.is_some());
+        assert!(!report.is_healthy());
+        assert_eq!(report.snapshots_checked, 2);
+        assert_eq!(report.duplicate_ids, vec![1]);
+
+        // So is a missing one, even without a backup
+        fs::remove_file(&metadata_path)?;
+        fs::remove_file(&backup_path)?;
+        let report = verify_repository(dir, None, None, None)?;
+        assert!(report.metadata_error.unwrap().contains("does not exist"));
+        assert_eq!(report.snapshots_checked, 0);
+        assert!(report.orphaned_blobs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_snapshots() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+        let file = Path::new(dir).join("file.txt");
+
+        initialize_timemachine(dir)?;
+        for version in 1..=4 {
+            fs::write(&file, format!("version {}", version))?;
+            take_snapshot(dir, &SnapshotOptions::default())?;
+        }
+        tag_snapshot(dir, 1, "release", None)?;
+
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            keep_tags: vec!["release".to_string()],
+            ..Default::default()
+        };
+        let report = prune_snapshots(dir, &policy, Duration::ZERO, true, None)?;
+        assert_eq!(report.kept, vec![1, 4]);
+        let removed: Vec<usize> = report.removed.iter().map(|s| s.id).collect();
+        assert_eq!(removed, vec![2, 3]);
+        assert!(report.gc.is_none());
+        assert_eq!(list_snapshots(dir, false)?.len(), 4);
+
+        let report = prune_snapshots(dir, &policy, Duration::ZERO, false, None)?;
+        assert_eq!(report.removed.len(), 2);
+        assert_eq!(report.gc.map(|gc| gc.removed_blobs.len()), Some(2));
+        let ids: Vec<usize> = list_snapshots(dir, false)?.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![1, 4]);
+        assert!(verify_repository(dir, None, None, None)?.is_healthy());
+
+        // Without any rule nothing is removed
+        let report = prune_snapshots(dir, &RetentionPolicy::default(), Duration::ZERO, false, None)?;
+        assert!(report.removed.is_empty());
+
+        Ok(())
+    }
+
     #[test]
     fn test_list_snapshots() -> io::Result<()> {
         let test_dir = tempdir()?;
@@ -522,7 +1543,7 @@ mod tests {
         writeln!(f1, "Hello, world!")?;
 
         // Take first snapshot
-        take_snapshot(dir)?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
 
         // Create another file
         let file2 = Path::new(dir).join("file2.txt");
@@ -530,7 +1551,7 @@ mod tests {
         writeln!(f2, "Second file")?;
 
         // Take second snapshot
-        take_snapshot(dir)?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
 
         // Test basic listing
         let snapshots = list_snapshots(dir, false)?;
@@ -538,7 +1559,7 @@ mod tests {
         assert_eq!(snapshots[0].id, 1);
         assert_eq!(snapshots[1].id, 2);
         assert_eq!(snapshots[0].changes, 1); // First snapshot has 1 file
-        assert_eq!(snapshots[1].changes, 2); // Second snapshot has 2 files
+        assert_eq!(snapshots[1].changes, 1); // Second snapshot adds 1 file
         assert_eq!(snapshots[0].total_size, 0); // Not detailed
 
         // Test detailed listing
@@ -550,6 +1571,65 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_snapshot_messages_and_tags() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+
+        fs::write(Path::new(dir).join("file1.txt"), "v1")?;
+        take_snapshot(dir, &SnapshotOptions { message: Some("before migration".to_string()), ..Default::default() })?;
+        fs::write(Path::new(dir).join("file1.txt"), "v2")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        tag_snapshot(dir, 1, "release-1.2", None)?;
+
+        let snapshots = list_snapshots(dir, false)?;
+        assert_eq!(snapshots[0].message.as_deref(), Some("before migration"));
+        assert_eq!(snapshots[0].tags, vec!["release-1.2"]);
+        assert!(snapshots[1].message.is_none());
+        assert!(snapshots[1].tags.is_empty());
+
+        // Tags and numeric IDs both resolve
+        assert_eq!(resolve_snapshot_ref(dir, "release-1.2")?, 1);
+        assert_eq!(resolve_snapshot_ref(dir, "2")?, 2);
+        assert!(resolve_snapshot_ref(dir, "missing").is_err());
+
+        // Tags must be unique and not look like IDs
+        assert!(tag_snapshot(dir, 2, "release-1.2", None).is_err());
+        assert!(tag_snapshot(dir, 2, "42", None).is_err());
+        assert!(tag_snapshot(dir, 99, "other", None).is_err());
+
+        untag_snapshot(dir, "release-1.2", None)?;
+        assert!(resolve_snapshot_ref(dir, "release-1.2").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_output_schema() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+        fs::write(Path::new(dir).join("file1.txt"), "v1")?;
+        take_snapshot(dir, &SnapshotOptions { message: Some("first".to_string()), ..Default::default() })?;
+
+        let snapshots = serde_json::to_value(list_snapshots(dir, true)?)?;
+        let keys: Vec<&String> = snapshots[0].as_object().unwrap().keys().collect();
+        assert_eq!(keys, vec!["changes", "id", "message", "tags", "timestamp", "total_size"]);
+
+        let status = serde_json::to_value(get_status(dir)?)?;
+        let keys: Vec<&String> = status.as_object().unwrap().keys().collect();
+        assert_eq!(
+            keys,
+            vec!["available_space", "deleted_files", "has_uncommitted_changes", "latest_snapshot_id", "modified_files", "new_files", "renamed_files"]
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_status() -> io::Result<()> {
         let test_dir = tempdir()?;
@@ -568,7 +1648,7 @@ mod tests {
         let file1 = Path::new(dir).join("file1.txt");
         let mut f1 = File::create(&file1)?;
         writeln!(f1, "Initial content")?;
-        take_snapshot(dir)?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
 
         // Test status with no changes
         let status = get_status(dir)?;
@@ -613,31 +1693,87 @@ mod tests {
         let file1 = Path::new(dir).join("file1.txt");
         let mut f1 = File::create(&file1)?;
         writeln!(f1, "File 1")?;
-        take_snapshot(dir)?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
 
         let file2 = Path::new(dir).join("file2.txt");
         let mut f2 = File::create(&file2)?;
         writeln!(f2, "File 2")?;
-        take_snapshot(dir)?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
 
         // Verify initial state
         let initial_snapshots = list_snapshots(dir, false)?;
         assert_eq!(initial_snapshots.len(), 2);
 
         // Test deleting non-existent snapshot
-        assert!(delete_snapshot(dir, 999, false).is_err());
+        assert!(matches!(
+            delete_snapshot(dir, 999, false, None),
+            Err(Error::SnapshotNotFound { id: 999, .. })
+        ));
 
         // Test deleting first snapshot without cleanup
-        delete_snapshot(dir, 1, false)?;
+        delete_snapshot(dir, 1, false, None)?;
         let remaining = list_snapshots(dir, false)?;
         assert_eq!(remaining.len(), 1);
         assert_eq!(remaining[0].id, 2);
 
         // Test deleting last snapshot with cleanup
-        delete_snapshot(dir, 2, true)?;
+        delete_snapshot(dir, 2, true, None)?;
         let final_snapshots = list_snapshots(dir, false)?;
         assert_eq!(final_snapshots.len(), 0);
 
         Ok(())
     }
+
+    #[test]
+    fn test_snapshot_ids_not_reused_after_delete() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+        let file = Path::new(dir).join("file1.txt");
+        fs::write(&file, "v1")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+        fs::write(&file, "v2")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // Deleting the newest snapshot does not free its ID
+        delete_snapshot(dir, 2, false, None)?;
+        fs::write(&file, "v3")?;
+        assert_eq!(take_snapshot(dir, &SnapshotOptions::default())?, Some(3));
+
+        // Nor does deleting every snapshot
+        delete_snapshot(dir, 1, true, None)?;
+        delete_snapshot(dir, 3, true, None)?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+        let snapshots = list_snapshots(dir, false)?;
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].id, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mutating_commands_respect_lock() -> io::Result<()> {
+        let test_dir = tempdir()?;
+        let dir = test_dir.path().to_str().unwrap();
+
+        initialize_timemachine(dir)?;
+        fs::write(Path::new(dir).join("file1.txt"), "File 1")?;
+        take_snapshot(dir, &SnapshotOptions::default())?;
+
+        // Mutating commands refuse to run while another process holds the lock
+        let lock = RepoLock::acquire(dir, None)?;
+        assert!(matches!(
+            take_snapshot(dir, &SnapshotOptions::default()),
+            Err(Error::Locked { .. })
+        ));
+        assert!(matches!(delete_snapshot(dir, 1, false, None), Err(Error::Locked { .. })));
+        assert!(matches!(tag_snapshot(dir, 1, "v1", None), Err(Error::Locked { .. })));
+        drop(lock);
+
+        tag_snapshot(dir, 1, "v1", None)?;
+        assert_eq!(list_snapshots(dir, false)?.len(), 1);
+
+        Ok(())
+    }
 }
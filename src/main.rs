@@ -1,8 +1,14 @@
 use clap::CommandFactory;
 use clap::Parser;
 use clap_complete::{generate_to, shells::*};
+use std::io;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
-use timemachine;
+use std::process::ExitCode;
+use std::time::Duration;
+use serde::Serialize;
+use serde_json::json;
+use timemachine::{ChangeStatus, DamagedBlob, Error, FileDiff, RenamedFile, RetentionPolicy, SnapshotOptions, VerifyReport};
 
 #[derive(Parser)]
 #[command(
@@ -15,6 +21,42 @@ use timemachine;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        help = "Wait for another timemachine process to release the repository",
+        long_help = "Commands that modify or verify a repository (snapshot, restore, delete, tag, untag, verify, gc, prune) take a lock on it. By default they fail immediately if another process holds the lock; with --wait they retry for up to SECONDS seconds."
+    )]
+    wait: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "Output format",
+        long_help = "Output format for command results. `text` prints human-readable messages to stderr; `json` prints a single JSON document to stdout; `ndjson` prints one compact JSON object per line to stdout (one per snapshot for list). Errors are always reported on stderr."
+    )]
+    format: OutputFormat,
+}
+
+/// Options shared by the commands that scan the working directory.
+#[derive(clap::Args)]
+struct ScanArgs {
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Rehash every file instead of trusting the scan cache",
+        long_help = "Scans reuse the hash of files whose size, modification time and inode have not changed since the previous scan. --rehash discards that cache so every file is read and hashed again, e.g. after a tool rewrote files while preserving their timestamps."
+    )]
+    rehash: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+    Ndjson,
 }
 
 #[derive(clap::Subcommand)]
@@ -43,6 +85,30 @@ enum Commands {
             long_help = "Path to an initialized directory. The directory must have been previously initialized using the init command."
         )]
         dir: String,
+        #[arg(
+            short,
+            long,
+            value_name = "MESSAGE",
+            help = "Describe the snapshot",
+            long_help = "A free-form message stored with the snapshot and shown by the list command, e.g. \"before migration\"."
+        )]
+        message: Option<String>,
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            help = "Number of files to hash and compress in parallel [default: number of CPUs]",
+            long_help = "Maximum number of worker threads used to hash and compress files. Defaults to the number of available CPUs; use 1 to process files one at a time."
+        )]
+        jobs: Option<NonZeroUsize>,
+        #[arg(
+            long,
+            help = "Record a snapshot even if nothing changed",
+            long_help = "By default no snapshot is recorded when the directory is identical to the latest snapshot, so scheduled runs do not clutter the history. With --allow-empty a snapshot is recorded anyway."
+        )]
+        allow_empty: bool,
+        #[command(flatten)]
+        scan: ScanArgs,
     },
 
     #[command(
@@ -76,6 +142,8 @@ enum Commands {
             long_help = "Path to an initialized directory. Shows changes made since the last snapshot, if any."
         )]
         dir: String,
+        #[command(flatten)]
+        scan: ScanArgs,
     },
 
     #[command(
@@ -90,11 +158,11 @@ enum Commands {
         )]
         dir: String,
         #[arg(
-            value_name = "SNAPSHOT_ID",
-            help = "ID of the snapshot to delete",
-            long_help = "Numeric ID of the snapshot to remove. Use the list command to see available snapshot IDs."
+            value_name = "SNAPSHOT",
+            help = "ID or tag of the snapshot to delete",
+            long_help = "Numeric ID or tag name of the snapshot to remove. Use the list command to see available snapshot IDs and tags."
         )]
-        snapshot_id: usize,
+        snapshot: String,
         #[arg(
             long,
             default_value_t = false,
@@ -105,8 +173,8 @@ enum Commands {
     },
 
     #[command(
-        about = "Compare two snapshots",
-        long_about = "Shows the differences between two snapshots, including added, modified, and deleted files. Useful for understanding changes between different points in time."
+        about = "Compare two snapshots, or a snapshot with the working directory",
+        long_about = "Shows the differences between two snapshots, including added, modified, and deleted files. With a single snapshot, compares it against the current contents of the directory. Useful for understanding changes between different points in time."
     )]
     Diff {
         #[arg(
@@ -116,17 +184,54 @@ enum Commands {
         )]
         dir: String,
         #[arg(
-            value_name = "SNAPSHOT_ID_1",
-            help = "ID of the first snapshot",
-            long_help = "Numeric ID of the first snapshot for comparison. Use the list command to see available snapshot IDs."
+            value_name = "SNAPSHOT_1",
+            help = "ID or tag of the first snapshot",
+            long_help = "Numeric ID or tag name of the first snapshot for comparison. Use the list command to see available snapshot IDs and tags."
         )]
-        snapshot_id1: usize,
+        snapshot1: String,
         #[arg(
-            value_name = "SNAPSHOT_ID_2",
-            help = "ID of the second snapshot",
-            long_help = "Numeric ID of the second snapshot for comparison. Use the list command to see available snapshot IDs."
+            value_name = "SNAPSHOT_2",
+            help = "ID or tag of the second snapshot (default: working directory)",
+            long_help = "Numeric ID or tag name of the second snapshot for comparison. Use the list command to see available snapshot IDs and tags. When omitted, SNAPSHOT_1 is compared against the current contents of the directory."
         )]
-        snapshot_id2: usize,
+        snapshot2: Option<String>,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Show line-level changes as unified diffs",
+            long_help = "Prints a unified diff for every changed text file by comparing the stored contents of both snapshots. Binary files are reported but not diffed."
+        )]
+        patch: bool,
+        #[arg(
+            long,
+            default_value_t = false,
+            help = "Show a per-file summary of inserted and deleted lines",
+            long_help = "Prints one line per changed file with the number of inserted and deleted lines, followed by a total. Can be combined with --patch."
+        )]
+        stat: bool,
+        #[arg(
+            short = 'U',
+            long = "context",
+            value_name = "LINES",
+            default_value_t = 3,
+            help = "Number of context lines around each change",
+            long_help = "Number of unchanged lines to show before and after each change in --patch output."
+        )]
+        context: usize,
+        #[arg(
+            short = 'M',
+            long = "find-renames",
+            value_name = "PERCENT",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "50",
+            value_parser = clap::value_parser!(u8).range(0..=100),
+            help = "Also detect renames with edits [default: 50]",
+            long_help = "Files with identical content are always reported as renames. With this option, a deleted and a new text file whose lines are at least PERCENT similar are reported as a rename with edits as well (default: 50)."
+        )]
+        find_renames: Option<u8>,
+        #[command(flatten)]
+        scan: ScanArgs,
     },
 
     #[command(
@@ -141,11 +246,11 @@ enum Commands {
         )]
         dir: String,
         #[arg(
-            value_name = "SNAPSHOT_ID",
-            help = "ID of the snapshot to restore to",
-            long_help = "Numeric ID of the snapshot to restore to. Use the list command to see available snapshot IDs."
+            value_name = "SNAPSHOT",
+            help = "ID or tag of the snapshot to restore to",
+            long_help = "Numeric ID or tag name of the snapshot to restore to. Use the list command to see available snapshot IDs and tags."
         )]
-        snapshot_id: usize,
+        snapshot: String,
         #[arg(
             long,
             default_value_t = false,
@@ -164,6 +269,229 @@ enum Commands {
             long_help = "Show what changes would be made without actually performing the restore operation."
         )]
         dry_run: bool,
+        #[arg(
+            long = "path",
+            value_name = "PATH",
+            help = "Restore only this file or directory (repeatable)",
+            long_help = "Restore only files at or below PATH, relative to the directory. May be given multiple times. Files outside the selected paths are never modified or deleted, and only the selected paths need to be free of uncommitted changes."
+        )]
+        paths: Vec<String>,
+        #[arg(
+            long,
+            value_name = "TARGET",
+            conflicts_with = "force",
+            help = "Restore into another, empty directory",
+            long_help = "Write the snapshot into TARGET instead of the tracked directory, e.g. to inspect an old version side by side with the current one. TARGET must be empty or not exist yet. The tracked directory is not modified and does not need to be free of uncommitted changes."
+        )]
+        to: Option<String>,
+        #[command(flatten)]
+        scan: ScanArgs,
+    },
+
+    #[command(
+        about = "Tag a snapshot with a name",
+        long_about = "Attaches a unique, human-readable name to a snapshot. Tags can be used anywhere a snapshot ID is accepted, such as restore, diff and delete. Use untag to remove a tag."
+    )]
+    Tag {
+        #[arg(
+            value_name = "DIRECTORY",
+            help = "Path to the directory",
+            long_help = "Path to an initialized directory containing the snapshot to tag."
+        )]
+        dir: String,
+        #[arg(
+            value_name = "SNAPSHOT",
+            help = "ID or tag of the snapshot to tag",
+            long_help = "Numeric ID or existing tag of the snapshot to tag."
+        )]
+        snapshot: String,
+        #[arg(
+            value_name = "TAG",
+            help = "Name of the tag",
+            long_help = "Tag name to attach. Tags must be unique across snapshots and cannot be purely numeric."
+        )]
+        tag: String,
+    },
+
+    #[command(
+        about = "Remove a tag",
+        long_about = "Removes a tag from whichever snapshot currently carries it. The snapshot itself is kept."
+    )]
+    Untag {
+        #[arg(
+            value_name = "DIRECTORY",
+            help = "Path to the directory",
+            long_help = "Path to an initialized directory containing the tagged snapshot."
+        )]
+        dir: String,
+        #[arg(
+            value_name = "TAG",
+            help = "Name of the tag to remove",
+            long_help = "Tag name to remove from the snapshot that carries it."
+        )]
+        tag: String,
+    },
+
+    #[command(
+        about = "Print a file from a snapshot",
+        long_about = "Writes the contents of a single file, as recorded in a snapshot, to standard output without restoring anything."
+    )]
+    Cat {
+        #[arg(
+            value_name = "DIRECTORY",
+            help = "Path to the directory",
+            long_help = "Path to an initialized directory containing the snapshot."
+        )]
+        dir: String,
+        #[arg(
+            value_name = "SNAPSHOT",
+            help = "ID or tag of the snapshot to read from",
+            long_help = "Numeric ID or tag of the snapshot that holds the file."
+        )]
+        snapshot: String,
+        #[arg(
+            value_name = "PATH",
+            help = "File to print, relative to DIRECTORY",
+            long_help = "Path of the file to print, relative to DIRECTORY, e.g. src/main.rs."
+        )]
+        path: String,
+    },
+
+    #[command(
+        visible_alias = "fsck",
+        about = "Check the repository for damage",
+        long_about = "Checks that the snapshot metadata parses, that snapshot IDs are unique, and that every stored file content referenced by a snapshot exists and matches its hash. Unreferenced contents are listed as well. Exits with status 6 if any damage is found."
+    )]
+    Verify {
+        #[arg(
+            value_name = "DIRECTORY",
+            help = "Path to the directory",
+            long_help = "Path to an initialized directory whose snapshots should be checked."
+        )]
+        dir: String,
+        #[arg(
+            long,
+            value_name = "PERCENT",
+            value_parser = clap::value_parser!(u8).range(1..=100),
+            help = "Only re-hash about PERCENT of the stored contents",
+            long_help = "Decompresses and re-hashes only a random PERCENT of the stored contents, which is much faster on large repositories. Every content is still checked for existence, and a different sample is picked on every run."
+        )]
+        sample: Option<u8>,
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            help = "Number of contents to check in parallel [default: number of CPUs]",
+            long_help = "Maximum number of worker threads used to decompress and re-hash stored contents. Defaults to the number of available CPUs."
+        )]
+        jobs: Option<NonZeroUsize>,
+    },
+
+    #[command(
+        about = "Remove stored contents no snapshot refers to",
+        long_about = "Marks every file content referenced by any snapshot and removes the stored contents that are left, reporting how much space was reclaimed. Contents written within the grace period are kept, since they may belong to a snapshot that is still being taken."
+    )]
+    Gc {
+        #[arg(
+            value_name = "DIRECTORY",
+            help = "Path to the directory",
+            long_help = "Path to an initialized directory whose content store should be cleaned up."
+        )]
+        dir: String,
+        #[arg(
+            long,
+            help = "Show what would be removed without removing anything",
+            long_help = "List the unreferenced contents and the space they use without deleting them."
+        )]
+        dry_run: bool,
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "1h",
+            value_parser = parse_duration,
+            help = "Keep unreferenced contents younger than this",
+            long_help = "Unreferenced contents modified more recently than DURATION are kept. Accepts a number followed by s, m, h or d, e.g. 30m or 2d; a bare number is read as seconds. Use 0 to remove every unreferenced content."
+        )]
+        grace: Duration,
+    },
+
+    #[command(
+        about = "Delete snapshots according to a retention policy",
+        long_about = "Keeps the snapshots selected by the --keep-* options and deletes all others, then removes stored contents no remaining snapshot refers to. A snapshot selected by any option is kept. Use --dry-run to list what would be deleted first.",
+        group = clap::ArgGroup::new("policy")
+            .required(true)
+            .multiple(true)
+            .args(["keep_last", "keep_hourly", "keep_daily", "keep_weekly", "keep_monthly", "keep_tag"])
+    )]
+    Prune {
+        #[arg(
+            value_name = "DIRECTORY",
+            help = "Path to the directory",
+            long_help = "Path to an initialized directory whose snapshots should be thinned out."
+        )]
+        dir: String,
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 0,
+            help = "Keep the N most recent snapshots",
+            long_help = "Always keep the N most recently taken snapshots."
+        )]
+        keep_last: usize,
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 0,
+            help = "Keep the last snapshot of each of the N most recent hours",
+            long_help = "For each of the N most recent hours that have snapshots, keep the last snapshot taken in that hour."
+        )]
+        keep_hourly: usize,
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 0,
+            help = "Keep the last snapshot of each of the N most recent days",
+            long_help = "For each of the N most recent days that have snapshots, keep the last snapshot taken on that day."
+        )]
+        keep_daily: usize,
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 0,
+            help = "Keep the last snapshot of each of the N most recent weeks",
+            long_help = "For each of the N most recent ISO weeks that have snapshots, keep the last snapshot taken in that week."
+        )]
+        keep_weekly: usize,
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 0,
+            help = "Keep the last snapshot of each of the N most recent months",
+            long_help = "For each of the N most recent months that have snapshots, keep the last snapshot taken in that month."
+        )]
+        keep_monthly: usize,
+        #[arg(
+            long,
+            value_name = "TAG",
+            help = "Keep every snapshot carrying TAG (repeatable)",
+            long_help = "Always keep snapshots that carry TAG. May be given several times to keep several tags."
+        )]
+        keep_tag: Vec<String>,
+        #[arg(
+            long,
+            help = "List the snapshots that would be deleted without deleting them",
+            long_help = "Show which snapshots would be kept and deleted, without changing anything."
+        )]
+        dry_run: bool,
+        #[arg(
+            long,
+            value_name = "DURATION",
+            default_value = "1h",
+            value_parser = parse_duration,
+            help = "Grace period for the content cleanup that follows",
+            long_help = "Unreferenced contents modified more recently than DURATION are kept by the cleanup after pruning, as with gc --grace."
+        )]
+        grace: Duration,
     },
 
     #[command(
@@ -181,8 +509,22 @@ enum Commands {
     },
 }
 
+impl Commands {
+    /// The directory whose scan cache to discard before running, for scanning commands
+    /// given `--rehash`.
+    fn rehash_directory(&self) -> Option<&str> {
+        match self {
+            Commands::Snapshot { dir, scan, .. }
+            | Commands::Status { dir, scan }
+            | Commands::Diff { dir, scan, .. }
+            | Commands::Restore { dir, scan, .. } if scan.rehash => Some(dir),
+            _ => None,
+        }
+    }
+}
+
 fn generate_completions(shell_name: Option<String>) -> std::io::Result<()> {
-    let shells = vec!["bash", "zsh", "fish", "powershell"];
+    let shells = ["bash", "zsh", "fish", "powershell"];
     let out_dir = PathBuf::from("completions");
     std::fs::create_dir_all(&out_dir)?;
 
@@ -207,10 +549,10 @@ fn generate_completions(shell_name: Option<String>) -> std::io::Result<()> {
                 generate_to(PowerShell, &mut cmd, &bin_name, &out_dir)?;
                 Ok(())
             }
-            _ => {
-                eprintln!("Unsupported shell. Available shells: {}", shells.join(", "));
-                Ok(())
-            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported shell. Available shells: {}", shells.join(", ")),
+            )),
         },
         None => {
             // Generate for all shells
@@ -224,51 +566,293 @@ fn generate_completions(shell_name: Option<String>) -> std::io::Result<()> {
     }
 }
 
-fn main() {
+// Process exit codes. Usage errors exit with 2, as reported by clap.
+const EXIT_FAILURE: u8 = 1;
+const EXIT_NOT_FOUND: u8 = 3;
+const EXIT_UNCOMMITTED_CHANGES: u8 = 4;
+const EXIT_LOCKED: u8 = 5;
+const EXIT_CORRUPT: u8 = 6;
+const EXIT_INSUFFICIENT_SPACE: u8 = 7;
+
+/// Prints a command result to stdout in the requested machine-readable format.
+fn print_json<T: Serialize>(format: OutputFormat, value: &T) {
+    let output = match format {
+        OutputFormat::Ndjson => serde_json::to_string(value),
+        _ => serde_json::to_string_pretty(value),
+    };
+    match output {
+        Ok(output) => println!("{}", output),
+        Err(e) => eprintln!("Failed to serialize output: {}", e),
+    }
+}
+
+/// Like `print_json`, but emits one line per item in ndjson mode.
+fn print_json_items<T: Serialize>(format: OutputFormat, items: &[T]) {
+    if format == OutputFormat::Ndjson {
+        for item in items {
+            print_json(format, item);
+        }
+    } else {
+        print_json(format, &items);
+    }
+}
+
+/// Maps library errors to process exit codes so that scripts can tell failures apart.
+fn exit_code(error: &Error) -> ExitCode {
+    let code = match error {
+        Error::SnapshotNotFound { .. } | Error::TagNotFound { .. } => EXIT_NOT_FOUND,
+        Error::PathNotFound { .. } => EXIT_NOT_FOUND,
+        Error::UncommittedChanges => EXIT_UNCOMMITTED_CHANGES,
+        Error::Locked { .. } => EXIT_LOCKED,
+        Error::CorruptMetadata { .. } | Error::ContentMissing { .. } => EXIT_CORRUPT,
+        Error::InsufficientSpace { .. } => EXIT_INSUFFICIENT_SPACE,
+        Error::TagExists { .. } | Error::InvalidTag { .. } | Error::TargetNotEmpty { .. } => EXIT_FAILURE,
+        Error::TargetInsideRepository { .. } => EXIT_FAILURE,
+        Error::Io(_) => EXIT_FAILURE,
+    };
+    ExitCode::from(code)
+}
+
+fn resolve_snapshot(dir: &str, reference: &str) -> Result<usize, ExitCode> {
+    timemachine::resolve_snapshot_ref(dir, reference).map_err(|e| {
+        eprintln!(
+            "Failed to find snapshot '{}' in directory '{}': {}. Use the list command to see available snapshots.",
+            reference, dir, e
+        );
+        exit_code(&e)
+    })
+}
+
+/// Widest bar drawn by `print_diff_stat`; larger changes are scaled down to fit.
+const STAT_BAR_WIDTH: usize = 40;
+
+fn print_diff_stat(diffs: &[FileDiff]) {
+    let labels: Vec<String> = diffs
+        .iter()
+        .map(|diff| match &diff.old_path {
+            Some(old_path) => format!("{} => {}", old_path, diff.path),
+            None => diff.path.clone(),
+        })
+        .collect();
+    let path_width = labels.iter().map(String::len).max().unwrap_or(0);
+    let largest = diffs.iter().map(|diff| diff.insertions + diff.deletions).max().unwrap_or(0);
+    let count_width = largest.to_string().len();
+
+    for (diff, label) in diffs.iter().zip(&labels) {
+        if diff.binary {
+            println!(" {:<path_width$} | {:>count_width$}", label, "Bin");
+            continue;
+        }
+        let total = diff.insertions + diff.deletions;
+        let (plus, minus) = if largest > STAT_BAR_WIDTH {
+            let scale = |n: usize| (n * STAT_BAR_WIDTH).div_ceil(largest);
+            (scale(diff.insertions), scale(diff.deletions))
+        } else {
+            (diff.insertions, diff.deletions)
+        };
+        let line = format!(
+            " {:<path_width$} | {:>count_width$} {}{}",
+            label,
+            total,
+            "+".repeat(plus),
+            "-".repeat(minus)
+        );
+        println!("{}", line.trim_end());
+    }
+
+    let insertions: usize = diffs.iter().map(|diff| diff.insertions).sum();
+    let deletions: usize = diffs.iter().map(|diff| diff.deletions).sum();
+    println!(
+        " {} file(s) changed, {} insertion(s)(+), {} deletion(s)(-)",
+        diffs.len(),
+        insertions,
+        deletions
+    );
+}
+
+fn describe_renames(renamed: &[RenamedFile]) -> Vec<String> {
+    renamed
+        .iter()
+        .map(|file| match file.similarity {
+            100 => format!("{} -> {}", file.old_path, file.new_path),
+            similarity => format!("{} -> {} ({}%)", file.old_path, file.new_path, similarity),
+        })
+        .collect()
+}
+
+fn print_patches(diffs: &[FileDiff]) {
+    for diff in diffs {
+        if let Some(old_path) = &diff.old_path {
+            println!("rename from {}", old_path);
+            println!("rename to {}", diff.path);
+        }
+        match &diff.patch {
+            Some(patch) => print!("{}", patch),
+            None => {
+                let old = match diff.status {
+                    ChangeStatus::Added => "/dev/null".to_string(),
+                    _ => format!("a/{}", diff.old_path.as_ref().unwrap_or(&diff.path)),
+                };
+                let new = match diff.status {
+                    ChangeStatus::Deleted => "/dev/null".to_string(),
+                    _ => format!("b/{}", diff.path),
+                };
+                println!("Binary files {} and {} differ", old, new);
+            }
+        }
+    }
+}
+
+fn print_damaged_blobs(label: &str, blobs: &[DamagedBlob]) {
+    if blobs.is_empty() {
+        return;
+    }
+    eprintln!("\n{} contents:", label);
+    for blob in blobs {
+        let ids: Vec<String> = blob.snapshot_ids.iter().map(|id| id.to_string()).collect();
+        eprintln!("  {} ({}), snapshots: {}", blob.hash, blob.paths.join(", "), ids.join(", "));
+    }
+}
+
+fn print_verify_report(report: &VerifyReport) {
+    if let Some(reason) = &report.metadata_error {
+        eprintln!("Metadata is damaged: {}", reason);
+    }
+    if !report.duplicate_ids.is_empty() {
+        eprintln!("Duplicate snapshot IDs: {:?}", report.duplicate_ids);
+    }
+    eprintln!(
+        "Checked {} snapshots referencing {} contents, re-hashed {} ({:.1} MB at {:.1} MB/s)",
+        report.snapshots_checked,
+        report.blobs_referenced,
+        report.blobs_verified,
+        report.bytes_verified as f64 / (1024.0 * 1024.0),
+        report.bytes_per_second / (1024.0 * 1024.0)
+    );
+    print_damaged_blobs("Missing", &report.missing_blobs);
+    print_damaged_blobs("Corrupt", &report.corrupt_blobs);
+    if !report.orphaned_blobs.is_empty() {
+        eprintln!(
+            "\n{} unreferenced contents (removed by delete --cleanup)",
+            report.orphaned_blobs.len()
+        );
+    }
+
+    if report.is_healthy() {
+        eprintln!("\nNo problems found");
+    } else {
+        eprintln!("\nThe repository is damaged");
+    }
+}
+
+/// Parses durations such as `90s`, `30m`, `1h` or `7d`. A bare number is read as seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit_secs) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 60 * 60),
+        Some((i, 'd')) => (&value[..i], 24 * 60 * 60),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit_secs))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration '{}', expected e.g. 30m, 1h or 7d", value))
+}
+
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    let wait = cli.wait.map(Duration::from_secs);
+    let format = cli.format;
+    let text = format == OutputFormat::Text;
+
+    if let Some(dir) = cli.command.rehash_directory() {
+        if let Err(e) = timemachine::clear_scan_cache(dir) {
+            eprintln!("Failed to clear the scan cache in directory '{}': {}", dir, e);
+            return exit_code(&e);
+        }
+    }
 
     match &cli.command {
         Commands::Init { dir } => match timemachine::initialize_timemachine(dir) {
-            Ok(_) => eprintln!("Initialization complete for {}", dir),
-            Err(e) => eprintln!(
-                "Initialization failed for directory '{}': {}. Please check the directory path and try again.",
-                dir, e
-            )
+            Ok(_) if text => eprintln!("Initialization complete for {}", dir),
+            Ok(_) => print_json(format, &json!({ "directory": dir })),
+            Err(e) => {
+                eprintln!(
+                    "Initialization failed for directory '{}': {}. Please check the directory path and try again.",
+                    dir, e
+                );
+                return exit_code(&e);
+            }
         },
-        Commands::Snapshot { dir } => match timemachine::take_snapshot(dir) {
-            Ok(_) => eprintln!("Snapshot for {} taken successfully!", dir),
-            Err(e) => eprintln!(
-                "Snapshot creation failed for directory '{}': {}. Please ensure the directory is accessible and try again.",
-                dir, e
-            ),
+        Commands::Snapshot { dir, message, jobs, allow_empty, .. } => match timemachine::take_snapshot(
+            dir,
+            &SnapshotOptions {
+                message: message.clone(),
+                wait,
+                jobs: jobs.map(NonZeroUsize::get),
+                allow_empty: *allow_empty,
+            },
+        ) {
+            Ok(Some(_)) if text => eprintln!("Snapshot for {} taken successfully!", dir),
+            Ok(None) if text => {
+                eprintln!("No changes in {} since the latest snapshot, nothing to record", dir);
+                if message.is_some() {
+                    eprintln!("Warning: the message was not saved; use --allow-empty to record the snapshot anyway");
+                }
+            }
+            Ok(Some(snapshot_id)) => print_json(format, &json!({ "id": snapshot_id, "skipped": false })),
+            Ok(None) => print_json(format, &json!({ "id": null, "skipped": true, "reason": "no changes" })),
+            Err(e) => {
+                eprintln!(
+                    "Snapshot creation failed for directory '{}': {}. Please ensure the directory is accessible and try again.",
+                    dir, e
+                );
+                return exit_code(&e);
+            }
         },
         Commands::List { dir, detailed } => match timemachine::list_snapshots(dir, *detailed) {
+            Ok(snapshots) if !text => print_json_items(format, &snapshots),
             Ok(snapshots) => {
                 if snapshots.is_empty() {
                     eprintln!("No snapshots found in {}", dir);
                 } else {
                     eprintln!("Snapshots in {}:", dir);
                     for snapshot in snapshots {
+                        let mut annotations = String::new();
+                        if !snapshot.tags.is_empty() {
+                            annotations.push_str(&format!(", Tags: {}", snapshot.tags.join(", ")));
+                        }
+                        if let Some(message) = &snapshot.message {
+                            annotations.push_str(&format!(", Message: {}", message));
+                        }
                         if *detailed {
                             eprintln!(
-                                "ID: {}, Time: {}, Changes: {}, Size: {} bytes",
-                                snapshot.id, snapshot.timestamp, snapshot.changes, snapshot.total_size
+                                "ID: {}, Time: {}, Changes: {}, Size: {} bytes{}",
+                                snapshot.id, snapshot.timestamp, snapshot.changes, snapshot.total_size, annotations
                             );
                         } else {
                             eprintln!(
-                                "ID: {}, Time: {}, Changes: {}",
-                                snapshot.id, snapshot.timestamp, snapshot.changes
+                                "ID: {}, Time: {}, Changes: {}{}",
+                                snapshot.id, snapshot.timestamp, snapshot.changes, annotations
                             );
                         }
                     }
                 }
             }
-            Err(e) => eprintln!(
-                "Failed to list snapshots in directory '{}': {}",
-                dir, e
-            ),
+            Err(e) => {
+                eprintln!(
+                    "Failed to list snapshots in directory '{}': {}",
+                    dir, e
+                );
+                return exit_code(&e);
+            }
         },
-        Commands::Status { dir } => match timemachine::get_status(dir) {
+        Commands::Status { dir, .. } => match timemachine::get_status(dir) {
+            Ok(status) if !text => print_json(format, &status),
             Ok(status) => {
                 eprintln!("Status for {}:", dir);
                 if let Some(id) = status.latest_snapshot_id {
@@ -289,66 +873,161 @@ fn main() {
                     if !status.deleted_files.is_empty() {
                         eprintln!("Deleted files: {:?}", status.deleted_files);
                     }
+                    if !status.renamed_files.is_empty() {
+                        eprintln!("Renamed files: {:?}", describe_renames(&status.renamed_files));
+                    }
                 } else {
                     eprintln!("\nWorking directory is clean");
                 }
             }
-            Err(e) => eprintln!(
-                "Failed to get status for directory '{}': {}",
-                dir, e
-            ),
+            Err(e) => {
+                eprintln!(
+                    "Failed to get status for directory '{}': {}",
+                    dir, e
+                );
+                return exit_code(&e);
+            }
         },
-        Commands::Delete { dir, snapshot_id, cleanup } => {
-            match timemachine::delete_snapshot(dir, *snapshot_id, *cleanup) {
+        Commands::Delete { dir, snapshot, cleanup } => {
+            let snapshot_id = match resolve_snapshot(dir, snapshot) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+            match timemachine::delete_snapshot(dir, snapshot_id, *cleanup, wait) {
+                Ok(_) if !text => print_json(format, &json!({ "deleted": snapshot_id })),
                 Ok(_) => {
                     eprintln!("Successfully deleted snapshot {}", snapshot_id);
                     if *cleanup {
                         eprintln!("Cleaned up unused content");
                     }
                 }
-                Err(e) => eprintln!(
-                    "Failed to delete snapshot {} in directory '{}': {}",
-                    snapshot_id, dir, e
-                ),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to delete snapshot {} in directory '{}': {}",
+                        snapshot_id, dir, e
+                    );
+                    return exit_code(&e);
+                }
             }
         }
         Commands::Diff {
             dir,
-            snapshot_id1,
-            snapshot_id2,
-        } => match timemachine::differentiate_snapshots(dir, *snapshot_id1, *snapshot_id2) {
-            Ok(comparison) => {
-                eprintln!(
-                    "Comparison between snapshot {} and snapshot {}:",
-                    snapshot_id1, snapshot_id2
-                );
-                eprintln!("New Files: {:?}", comparison.new_files);
-                eprintln!("Modified Files: {:?}", comparison.modified_files);
-                eprintln!("Deleted Files: {:?}", comparison.deleted_files);
+            snapshot1,
+            snapshot2,
+            patch,
+            stat,
+            context,
+            find_renames,
+            ..
+        } => {
+            let snapshot_id1 = match resolve_snapshot(dir, snapshot1) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+            // Without a second snapshot the working tree is the new side
+            let snapshot_id2 = match snapshot2.as_deref().map(|reference| resolve_snapshot(dir, reference)) {
+                Some(Ok(id)) => Some(id),
+                Some(Err(code)) => return code,
+                None => None,
+            };
+            let new_side = match snapshot_id2 {
+                Some(id) => format!("snapshot {}", id),
+                None => "the working directory".to_string(),
+            };
+            if *patch || *stat {
+                let result = match snapshot_id2 {
+                    Some(id2) => timemachine::diff_snapshot_contents(dir, snapshot_id1, id2, *context, *find_renames),
+                    None => timemachine::diff_working_tree_contents(dir, snapshot_id1, *context, *find_renames),
+                };
+                match result {
+                    Ok(mut diffs) if !text => {
+                        if !*patch {
+                            diffs.iter_mut().for_each(|diff| diff.patch = None);
+                        }
+                        print_json_items(format, &diffs);
+                    }
+                    Ok(diffs) => {
+                        if *stat {
+                            print_diff_stat(&diffs);
+                        }
+                        if *patch {
+                            print_patches(&diffs);
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to compare snapshot {} and {} in directory '{}': {}",
+                            snapshot_id1, new_side, dir, e
+                        );
+                        return exit_code(&e);
+                    }
+                }
+                return ExitCode::SUCCESS;
             }
-            Err(e) => eprintln!(
-                "Failed to compare snapshots {} and {} in directory '{}': {}. Ensure the snapshots exist and try again.",
-                snapshot_id1, snapshot_id2, dir, e
-            ),
-        },
+            let result = match snapshot_id2 {
+                Some(id2) => timemachine::differentiate_snapshots(dir, snapshot_id1, id2, *find_renames),
+                None => timemachine::differentiate_working_tree(dir, snapshot_id1, *find_renames),
+            };
+            match result {
+                Ok(comparison) if !text => print_json(format, &comparison),
+                Ok(comparison) => {
+                    eprintln!(
+                        "Comparison between snapshot {} and {}:",
+                        snapshot_id1, new_side
+                    );
+                    eprintln!("New Files: {:?}", comparison.new_files);
+                    eprintln!("Modified Files: {:?}", comparison.modified_files);
+                    eprintln!("Deleted Files: {:?}", comparison.deleted_files);
+                    eprintln!("Renamed Files: {:?}", describe_renames(&comparison.renamed_files));
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Failed to compare snapshot {} and {} in directory '{}': {}. Ensure the snapshots exist and try again.",
+                        snapshot_id1, new_side, dir, e
+                    );
+                    return exit_code(&e);
+                }
+            }
+        }
         Commands::Restore {
             dir,
-            snapshot_id,
+            snapshot,
             dry_run,
-            force
+            force,
+            paths,
+            to,
+            ..
         } =>
             {
-                eprintln!("Preparing to restore directory: {}", dir);
-                if *force {
+                let snapshot_id = match resolve_snapshot(dir, snapshot) {
+                    Ok(id) => id,
+                    Err(code) => return code,
+                };
+                if text {
+                    eprintln!("Preparing to restore directory: {}", dir);
+                }
+                if *force && text {
                     eprintln!("WARNING: Force flag is enabled. This will:");
                     eprintln!("  1. Create a backup snapshot of your current state");
                     eprintln!("  2. Override any uncommitted changes");
                     eprintln!("  3. Restore to the specified snapshot");
                 }
 
-            match timemachine::restore_snapshot(dir, *snapshot_id, *dry_run, *force) {
+            let result = if let Some(target) = to {
+                timemachine::restore_to(dir, snapshot_id, target, paths, *dry_run, wait)
+            } else if paths.is_empty() {
+                timemachine::restore_snapshot(dir, snapshot_id, *dry_run, *force, wait)
+            } else {
+                timemachine::restore_paths(dir, snapshot_id, paths, *dry_run, *force, wait)
+            };
+            match result {
+                Ok(report) if !text => print_json(format, &report),
                 Ok(report) => {
-                    if report.added.is_empty() && report.modified.is_empty() && report.deleted.is_empty() {
+                    if report.added.is_empty()
+                        && report.modified.is_empty()
+                        && report.deleted.is_empty()
+                        && report.renamed.is_empty()
+                    {
                         eprintln!("No changes needed - files are already at the target state.");
                     } else {
                         eprintln!("Changes to be made:");
@@ -361,6 +1040,9 @@ fn main() {
                         if !report.deleted.is_empty() {
                             eprintln!("Files to delete: {:?}", report.deleted);
                         }
+                        if !report.renamed.is_empty() {
+                            eprintln!("Files to move: {:?}", describe_renames(&report.renamed));
+                        }
                     }
 
                     if *dry_run {
@@ -369,16 +1051,159 @@ fn main() {
                         eprintln!("Restore complete.");
                     }
                 }
-                Err(e) => eprintln!(
-                    "Failed to restore snapshot {} in directory '{}': {}",
-                    snapshot_id, dir, e
-                ),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to restore snapshot {} in directory '{}': {}",
+                        snapshot_id, dir, e
+                    );
+                    return exit_code(&e);
+                }
             }
         },
+        Commands::Tag { dir, snapshot, tag } => {
+            let snapshot_id = match resolve_snapshot(dir, snapshot) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+            match timemachine::tag_snapshot(dir, snapshot_id, tag, wait) {
+                Ok(_) if text => eprintln!("Tagged snapshot {} as '{}'", snapshot_id, tag),
+                Ok(_) => print_json(format, &json!({ "id": snapshot_id, "tag": tag })),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to tag snapshot {} in directory '{}': {}",
+                        snapshot_id, dir, e
+                    );
+                    return exit_code(&e);
+                }
+            }
+        }
+        Commands::Untag { dir, tag } => match timemachine::untag_snapshot(dir, tag, wait) {
+            Ok(_) if text => eprintln!("Removed tag '{}'", tag),
+            Ok(_) => print_json(format, &json!({ "removed_tag": tag })),
+            Err(e) => {
+                eprintln!(
+                    "Failed to remove tag '{}' in directory '{}': {}",
+                    tag, dir, e
+                );
+                return exit_code(&e);
+            }
+        },
+        Commands::Cat { dir, snapshot, path } => {
+            let snapshot_id = match resolve_snapshot(dir, snapshot) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+            let mut reader = match timemachine::open_snapshot_file(dir, snapshot_id, path) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    eprintln!(
+                        "Failed to read '{}' from snapshot {} in directory '{}': {}",
+                        path, snapshot_id, dir, e
+                    );
+                    return exit_code(&e);
+                }
+            };
+            match io::copy(&mut reader, &mut io::stdout().lock()) {
+                Ok(_) => {}
+                // The reader went away, e.g. `timemachine cat ... | head`
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+                Err(e) => {
+                    eprintln!(
+                        "Failed to read '{}' from snapshot {} in directory '{}': {}",
+                        path, snapshot_id, dir, e
+                    );
+                    return ExitCode::from(EXIT_FAILURE);
+                }
+            }
+        }
+        Commands::Verify { dir, sample, jobs } => match timemachine::verify_repository(dir, *sample, jobs.map(NonZeroUsize::get), wait) {
+            Ok(report) => {
+                if text {
+                    print_verify_report(&report);
+                } else {
+                    print_json(format, &report);
+                }
+                if !report.is_healthy() {
+                    return ExitCode::from(EXIT_CORRUPT);
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to verify directory '{}': {}", dir, e);
+                return exit_code(&e);
+            }
+        },
+        Commands::Gc { dir, dry_run, grace } => match timemachine::collect_garbage(dir, *grace, *dry_run, wait) {
+            Ok(report) if !text => print_json(format, &report),
+            Ok(report) => {
+                if report.dry_run {
+                    for hash in &report.removed_blobs {
+                        eprintln!("Would remove {}", hash);
+                    }
+                    eprintln!("Would reclaim {} bytes from {} unreferenced contents", report.reclaimed_bytes, report.removed_blobs.len());
+                } else {
+                    eprintln!("Reclaimed {} bytes from {} unreferenced contents", report.reclaimed_bytes, report.removed_blobs.len());
+                }
+                if report.recent_blobs > 0 {
+                    eprintln!("Kept {} unreferenced contents younger than the grace period", report.recent_blobs);
+                }
+            }
+            Err(e) => {
+                eprintln!("Garbage collection failed for directory '{}': {}", dir, e);
+                return exit_code(&e);
+            }
+        },
+        Commands::Prune {
+            dir,
+            keep_last,
+            keep_hourly,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            keep_tag,
+            dry_run,
+            grace,
+        } => {
+            let policy = RetentionPolicy {
+                keep_last: *keep_last,
+                keep_hourly: *keep_hourly,
+                keep_daily: *keep_daily,
+                keep_weekly: *keep_weekly,
+                keep_monthly: *keep_monthly,
+                keep_tags: keep_tag.clone(),
+            };
+            match timemachine::prune_snapshots(dir, &policy, *grace, *dry_run, wait) {
+                Ok(report) if !text => print_json(format, &report),
+                Ok(report) => {
+                    let verb = if report.dry_run { "Would delete" } else { "Deleted" };
+                    for snapshot in &report.removed {
+                        eprintln!("{} snapshot {} ({})", verb, snapshot.id, snapshot.timestamp);
+                    }
+                    eprintln!(
+                        "{} {} snapshots, keeping {}",
+                        verb,
+                        report.removed.len(),
+                        report.kept.len()
+                    );
+                    if let Some(gc) = &report.gc {
+                        eprintln!(
+                            "Reclaimed {} bytes from {} unreferenced contents",
+                            gc.reclaimed_bytes,
+                            gc.removed_blobs.len()
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to prune snapshots in directory '{}': {}", dir, e);
+                    return exit_code(&e);
+                }
+            }
+        }
         Commands::Completions { shell } => {
             if let Err(e) = generate_completions(shell.clone()) {
                 eprintln!("Failed to generate completions: {}", e);
+                return ExitCode::from(EXIT_FAILURE);
             }
         }
     }
+    ExitCode::SUCCESS
 }